@@ -0,0 +1,342 @@
+//! Optional access log of playlist and segment requests, as JSON lines or in Apache's combined
+//! format. Client IPs are never written as-is: they're either hashed with a key that changes
+//! every restart, or truncated to their network. Only the route a request matched is logged,
+//! not its path or query, which can carry signed URLs.
+//!
+//! Handlers add what they learn about a request (stream, proxy, upstream timings) through the
+//! functions here, which do nothing unless the access log is on.
+
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::hash::{BuildHasher, RandomState};
+use std::io::{self, LineWriter, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use clap::ValueEnum;
+use http::{Method, StatusCode, Version, header::CONTENT_LENGTH};
+use serde_json::json;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::{LState, Opts, StreamID};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum Format {
+    /// One JSON object per line.
+    Json,
+    /// Apache's combined format, with the extra details appended as `key=value`.
+    Combined,
+}
+
+/// How client IPs are anonymised.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub(crate) enum ClientIp {
+    /// A keyed hash, so one client's requests can be told apart from another's. The key is
+    /// random and changes every restart.
+    Hash,
+    /// The /24 (IPv4) or /48 (IPv6) the client is in.
+    Truncate,
+}
+
+/// What handlers found out about a request.
+#[derive(Debug, Default)]
+struct Details {
+    sid: Option<String>,
+    /// Label of the last proxy tried.
+    via: Option<String>,
+    country: Option<String>,
+    error: Option<&'static str>,
+    /// Upstream requests, in order. Empty when served from a fetch shared with another request.
+    stages: Vec<(&'static str, Duration)>,
+}
+
+tokio::task_local! {
+    static DETAILS: RefCell<Details>;
+}
+
+fn note(f: impl FnOnce(&mut Details)) {
+    let _ = DETAILS.try_with(|details| f(&mut details.borrow_mut()));
+}
+
+pub(crate) fn stream(sid: &StreamID) {
+    let sid = match sid {
+        StreamID::Live(channel) => format!("live/{channel}"),
+        StreamID::VOD(id) => format!("vod/{id}"),
+        StreamID::Clip(slug) => format!("clip/{slug}"),
+    };
+    note(|d| d.sid = Some(sid));
+}
+
+pub(crate) fn via(label: &str) {
+    note(|d| d.via = Some(label.to_owned()));
+}
+
+pub(crate) fn country(country: &str) {
+    note(|d| d.country = Some(country.to_owned()));
+}
+
+pub(crate) fn error(kind: &'static str) {
+    note(|d| d.error = Some(kind));
+}
+
+pub(crate) fn stage(stage: &'static str, elapsed: Duration) {
+    note(|d| d.stages.push((stage, elapsed)));
+}
+
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    format: Format,
+    client_ip: ClientIp,
+    key: RandomState,
+    /// Lines go to a thread of their own, so slow disks don't hold up requests.
+    lines: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    /// Open the log if one is configured; `-` means stdout.
+    pub(crate) fn open(opts: &Opts) -> Result<Option<Self>> {
+        let Some(path) = &opts.access_log else {
+            return Ok(None);
+        };
+        let out: Box<dyn Write + Send> = if path == Path::new("-") {
+            Box::new(io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("can't open access log {}", path.display()))?;
+            Box::new(LineWriter::new(file))
+        };
+        let (lines, received) = mpsc::channel::<String>();
+        std::thread::Builder::new().name("access-log".to_owned()).spawn(move || {
+            let mut out = out;
+            for line in received {
+                if let Err(e) = writeln!(out, "{line}").and_then(|_| out.flush()) {
+                    error!("failed to write access log: {e}");
+                }
+            }
+        })?;
+        Ok(Some(Self {
+            format: opts.access_log_format,
+            client_ip: opts.access_log_client_ip,
+            key: RandomState::new(),
+            lines,
+        }))
+    }
+
+    fn client(&self, ip: IpAddr) -> String {
+        match self.client_ip {
+            ClientIp::Hash => format!("{:016x}", self.key.hash_one(ip.to_canonical())),
+            ClientIp::Truncate => truncate(ip).to_string(),
+        }
+    }
+}
+
+fn truncate(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V4(v4) => IpAddr::V4(Ipv4Addr::from(u32::from(v4) & !0xff)),
+        IpAddr::V6(v6) => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u128::MAX >> 48))),
+    }
+}
+
+/// One finished request.
+struct Entry {
+    time: SystemTime,
+    client: String,
+    method: Method,
+    route: String,
+    version: Version,
+    status: StatusCode,
+    bytes: Option<u64>,
+    took: Duration,
+    details: Details,
+}
+
+impl Entry {
+    fn json(&self) -> String {
+        let d = &self.details;
+        let stages: Vec<_> = d
+            .stages
+            .iter()
+            .map(|(stage, took)| json!({"stage": stage, "ms": millis(*took)}))
+            .collect();
+        json!({
+            "time": timestamp(self.time, "%Y-%m-%dT%H:%M:%SZ"),
+            "client": self.client,
+            "method": self.method.as_str(),
+            "route": self.route,
+            "sid": d.sid,
+            "status": self.status.as_u16(),
+            "error": d.error,
+            "ms": millis(self.took),
+            "stages": stages,
+            "via": d.via,
+            "country": d.country,
+        })
+        .to_string()
+    }
+
+    /// Referer and User-Agent are left out, as `-`.
+    fn combined(&self) -> String {
+        let d = &self.details;
+        let mut line = format!(
+            r#"{} - - [{}] "{} {} {:?}" {} {} "-" "-""#,
+            self.client,
+            timestamp(self.time, "%d/%b/%Y:%H:%M:%S +0000"),
+            self.method,
+            self.route,
+            self.version,
+            self.status.as_u16(),
+            self.bytes.map_or_else(|| "-".to_owned(), |b| b.to_string()),
+        );
+        let fields = [("sid", &d.sid), ("via", &d.via), ("country", &d.country)];
+        for (key, value) in fields {
+            if let Some(value) = value {
+                line.push_str(&format!(" {key}={}", value.replace(' ', "_")));
+            }
+        }
+        if let Some(error) = d.error {
+            line.push_str(&format!(" error={error}"));
+        }
+        for (stage, took) in &d.stages {
+            line.push_str(&format!(" {stage}={}ms", millis(*took)));
+        }
+        line.push_str(&format!(" ms={}", millis(self.took)));
+        line
+    }
+}
+
+/// Whole milliseconds, for reports.
+pub(crate) fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// Format a UTC time, supporting just the strftime fields used here.
+fn timestamp(time: SystemTime, format: &str) -> String {
+    const MONTHS: [&str; 12] =
+        ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    // https://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (secs / 86_400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format
+        .replace("%Y", &year.to_string())
+        .replace("%m", &format!("{month:02}"))
+        .replace("%b", MONTHS[month as usize - 1])
+        .replace("%d", &format!("{day:02}"))
+        .replace("%H", &format!("{:02}", secs % 86_400 / 3600))
+        .replace("%M", &format!("{:02}", secs % 3600 / 60))
+        .replace("%S", &format!("{:02}", secs % 60))
+}
+
+pub(crate) async fn record(
+    State(state): State<LState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let Some(log) = state.access_log else {
+        return next.run(req).await;
+    };
+    let (time, started) = (SystemTime::now(), Instant::now());
+    let client = log.client(state.limiter.client_ip(peer.ip(), req.headers()));
+    let method = req.method().clone();
+    let version = req.version();
+    let route = req.extensions().get::<MatchedPath>().map_or("-", |p| p.as_str()).to_owned();
+    let (response, details) = DETAILS
+        .scope(RefCell::default(), async move {
+            let response = next.run(req).await;
+            (response, DETAILS.with(|d| d.take()))
+        })
+        .await;
+    let entry = Entry {
+        time,
+        client,
+        method,
+        route,
+        version,
+        status: response.status(),
+        bytes: response
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok()),
+        took: started.elapsed(),
+        details,
+    };
+    let line = match log.format {
+        Format::Json => entry.json(),
+        Format::Combined => entry.combined(),
+    };
+    let _ = log.lines.send(line); // only fails if the writer thread died, which it logged
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use http::{Method, StatusCode, Version};
+
+    use crate::access_log::{Details, Entry, timestamp, truncate};
+
+    #[test]
+    fn timestamps() {
+        let time = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        assert_eq!(timestamp(time, "%Y-%m-%dT%H:%M:%SZ"), "2023-11-14T22:13:20Z");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400); // 2000-02-29
+        assert_eq!(timestamp(leap, "%d/%b/%Y:%H:%M:%S"), "29/Feb/2000:00:00:00");
+    }
+
+    #[test]
+    fn truncates_to_network() {
+        assert_eq!(truncate("203.0.113.7".parse().unwrap()).to_string(), "203.0.113.0");
+        assert_eq!(truncate("2001:db8:1:2:3::4".parse().unwrap()).to_string(), "2001:db8:1::");
+        assert_eq!(truncate("::ffff:203.0.113.7".parse().unwrap()).to_string(), "203.0.113.0");
+    }
+
+    #[test]
+    fn combined() {
+        let entry = Entry {
+            time: UNIX_EPOCH + Duration::from_secs(1_700_000_000),
+            client: "203.0.113.0".to_owned(),
+            method: Method::GET,
+            route: "/live/{id}".to_owned(),
+            version: Version::HTTP_11,
+            status: StatusCode::OK,
+            bytes: Some(512),
+            took: Duration::from_millis(120),
+            details: Details {
+                sid: Some("live/abc".to_owned()),
+                via: Some("http://10.0.0.1:8080".to_owned()),
+                country: Some("RU".to_owned()),
+                error: None,
+                stages: vec![
+                    ("token", Duration::from_millis(40)),
+                    ("m3u8", Duration::from_millis(70)),
+                ],
+            },
+        };
+        assert_eq!(
+            entry.combined(),
+            r#"203.0.113.0 - - [14/Nov/2023:22:13:20 +0000] "GET /live/{id} HTTP/1.1" 200 512 "-" "-" sid=live/abc via=http://10.0.0.1:8080 country=RU token=40ms m3u8=70ms ms=120"#
+        );
+    }
+}
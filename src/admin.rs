@@ -0,0 +1,151 @@
+//! Changing proxies while running, for operators. Lives under `/admin/SECRET`, like the deep
+//! status endpoint, and only exists if `--admin-secret` is set. Routes are swapped rather than
+//! changed in place, so requests already using the old ones finish normally.
+
+use axum::{
+    Json, Router,
+    extract::{Query, State},
+    http::StatusCode,
+    routing::{any, get, post},
+};
+use serde::Deserialize;
+use serde_json::{Value, json};
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::error::{AppError, AppResult};
+use crate::pool::{RouteSpec, Source};
+use crate::{LState, ratelimit};
+
+pub(crate) fn router(secret: &str, state: LState) -> Router {
+    let admin = Router::new().route("/", get(show)).route("/proxy", post(set_proxy));
+    #[cfg(feature = "hola")]
+    let admin =
+        admin.route("/hola/login", post(hola::login)).route("/hola/tunnels", post(hola::tunnels));
+    let limiter = state.limiter.clone();
+    // wrong secrets are answered here too, so guessing counts against the rate limit
+    Router::new()
+        .nest(&format!("/admin/{secret}"), admin.with_state(state))
+        .route("/admin/{*rest}", any(|| async { StatusCode::NOT_FOUND }))
+        .layer(axum::middleware::from_fn_with_state(limiter, ratelimit::limit))
+}
+
+/// The pool's routes, and the Hola session if there is one.
+async fn show(State(state): State<LState>) -> Json<Value> {
+    let routes: Vec<_> = state
+        .pool
+        .all()
+        .iter()
+        .map(|r| {
+            json!({
+                "label": r.label(), // never has credentials
+                "source": format!("{:?}", r.spec.source).to_ascii_lowercase(),
+                "country": r.spec.country,
+                "priority": r.spec.priority,
+                "healthy": r.is_healthy(),
+            })
+        })
+        .collect();
+    #[allow(unused_mut)]
+    let mut status = json!({ "routes": routes });
+    #[cfg(feature = "hola")]
+    if let Some(hola) = &state.hola {
+        let (uuid, key) = hola.credentials().await;
+        status["hola"] = json!({
+            "uuid": uuid.as_simple().to_string(),
+            "key": mask(&key.to_string()),
+            "countries": hola.countries(),
+        });
+    }
+    Json(status)
+}
+
+/// Keep just enough of a secret to tell whether it's changed.
+#[cfg(feature = "hola")]
+fn mask(secret: &str) -> String {
+    let shown: String = secret.chars().take(2).collect();
+    format!("{shown}{}", "*".repeat(secret.chars().count().saturating_sub(2)))
+}
+
+#[derive(Deserialize)]
+struct ProxyQuery {
+    url: String,
+}
+
+/// Replace every route with one through the given proxy, as if started with `--proxy URL`. It
+/// stays until replaced here or by Hola, even if the config file is reloaded.
+async fn set_proxy(
+    State(state): State<LState>,
+    Query(query): Query<ProxyQuery>,
+) -> AppResult<Json<Value>> {
+    let spec = Url::parse(&query.url)
+        .map_err(anyhow::Error::from)
+        .and_then(|url| RouteSpec::from_url(&url))
+        .map(|spec| RouteSpec { source: Source::Admin, ..spec })
+        .map_err(|e| AppError::BadRequest(format!("invalid proxy URL: {e}")))?;
+    info!("admin: switching every request to {}", spec.label);
+    #[cfg(feature = "hola")]
+    if let Some(hola) = &state.hola {
+        hola.pause();
+    }
+    state.pool.replace(|_| true, vec![spec])?;
+    state.tokens.clear(); // tied to the old proxies' IPs
+    Ok(show(State(state)).await)
+}
+
+#[cfg(feature = "hola")]
+mod hola {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn session(state: &LState) -> AppResult<&crate::hello_config::Hola> {
+        state.hola.as_deref().ok_or_else(|| AppError::BadRequest("not using Hola".to_owned()))
+    }
+
+    /// Log in to Hola with new credentials and swap in tunnels fetched with them.
+    pub(super) async fn login(State(state): State<LState>) -> AppResult<Json<Value>> {
+        info!("admin: regenerating Hola credentials");
+        session(&state)?.relogin(&state.pool).await?;
+        if let Some(countries) = &state.countries {
+            countries.clear(); // set up with the old session
+        }
+        Ok(show(State(state)).await)
+    }
+
+    /// Fetch new Hola tunnels, optionally for a new list of `countries`. Also switches back to
+    /// Hola after `/proxy`.
+    pub(super) async fn tunnels(
+        State(state): State<LState>,
+        Query(query): Query<HashMap<String, String>>,
+    ) -> AppResult<Json<Value>> {
+        let hola = session(&state)?;
+        let previous = hola.countries();
+        if let Some(countries) = query.get("countries") {
+            let countries = countries
+                .split(',')
+                .map(crate::parse_country)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+            info!("admin: switching Hola countries to {}", countries.join(","));
+            hola.set_countries(countries);
+        }
+        if let Err(e) = hola.refresh(&state.pool).await {
+            hola.set_countries(previous); // or the next scheduled refresh would use them
+            return Err(e.into());
+        }
+        Ok(show(State(state)).await)
+    }
+}
+
+#[cfg(all(test, feature = "hola"))]
+mod tests {
+    use crate::admin::mask;
+
+    #[test]
+    fn masks_all_but_the_start() {
+        assert_eq!(mask("123456789"), "12*******");
+        assert_eq!(mask("1"), "1");
+    }
+}
@@ -0,0 +1,141 @@
+//! Heuristics for spotting a playlist that's going to have ads in it, so it can be fetched again
+//! through another route. None of this is documented by Twitch, so it can't be exact.
+
+use std::fmt;
+
+use serde::Deserialize;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::PlaybackAccessToken;
+use crate::m3u8::{Item, Playlist};
+use crate::strip::is_ad_break;
+
+/// SCTE-35 cue tags, which mark where an ad break starts.
+const CUE_TAGS: &[&str] = &["#EXT-X-SCTE35-OUT", "#EXT-X-CUE-OUT"];
+
+/// The parts of the token's `value` JSON that say whether this viewer gets ads at all.
+#[derive(Deserialize)]
+struct AdFlags {
+    show_ads: Option<bool>,
+    hide_ads: Option<bool>,
+}
+
+/// Why a playlist is believed to come with ads.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum AdSign {
+    /// The master playlist carries ad-related session data.
+    SessionData(String),
+    /// The playlist announces a stitched ad break, by the `ID` or `CLASS` given, or a cue tag.
+    AdBreak(String),
+}
+
+impl AdSign {
+    /// Short, stable name, for headers and metrics.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::SessionData(_) => "session_data",
+            Self::AdBreak(_) => "ad_break",
+        }
+    }
+}
+
+impl fmt::Display for AdSign {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SessionData(id) => write!(f, "ad session data {id}"),
+            Self::AdBreak(marker) => write!(f, "ad break {marker}"),
+        }
+    }
+}
+
+/// The country Twitch believes the request came from, if the playlist says.
+pub(crate) fn user_country(m3u8: &Playlist) -> Option<&str> {
+    m3u8.twitch_info()?.user_country()
+}
+
+/// Check a master playlist, and the token it was fetched with, for signs of ads. The country is
+/// checked separately, since a proxy in the wrong country is a failure rather than a maybe.
+pub(crate) fn detect(token: &PlaybackAccessToken, m3u8: &Playlist) -> Option<AdSign> {
+    if let Ok(flags) = serde_json::from_str::<AdFlags>(&token.value)
+        && (flags.hide_ads == Some(true) || flags.show_ads == Some(false))
+    {
+        return None; // e.g. Turbo or a subscription; nothing else matters
+    }
+    if let Some(id) = m3u8
+        .session_data()
+        .filter_map(|data| data.get("DATA-ID"))
+        .find(|id| id.split(['.', '-', '_']).any(|part| part == "ad" || part == "ads"))
+    {
+        return Some(AdSign::SessionData(id.to_owned()));
+    }
+    m3u8.lines.iter().find_map(|line| match &line.item {
+        Item::DateRange(daterange) if is_ad_break(daterange) => {
+            let marker = daterange.get("CLASS").or(daterange.get("ID")).unwrap_or("daterange");
+            Some(AdSign::AdBreak(marker.to_owned()))
+        }
+        Item::Tag { name, .. } | Item::Other(name) => CUE_TAGS
+            .iter()
+            .find(|cue| name.starts_with(*cue))
+            .map(|cue| AdSign::AdBreak(cue.trim_start_matches('#').to_owned())),
+        _ => None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PlaybackAccessToken;
+    use crate::ads::{AdSign, detect};
+    use crate::m3u8::Playlist;
+
+    fn token(value: &str) -> PlaybackAccessToken {
+        PlaybackAccessToken { value: value.to_owned(), signature: String::new() }
+    }
+
+    fn master(country: &str, extra: &str) -> Playlist {
+        Playlist::parse(&format!(
+            "#EXTM3U\n{extra}#EXT-X-TWITCH-INFO:NODE=\"video-edge\",USER-COUNTRY=\"{country}\"\n"
+        ))
+    }
+
+    #[test]
+    fn detects_ads() {
+        let anon = token(r#"{"show_ads":true,"hide_ads":false}"#);
+        assert_eq!(detect(&anon, &master("RU", "")), None);
+        let data = "#EXT-X-SESSION-DATA:DATA-ID=\"com.twitch.ads.preroll\",VALUE=\"1\"\n";
+        assert_eq!(
+            detect(&anon, &master("RU", data)),
+            Some(AdSign::SessionData("com.twitch.ads.preroll".to_owned()))
+        );
+        let unrelated = "#EXT-X-SESSION-DATA:DATA-ID=\"com.twitch.loading\",VALUE=\"1\"\n";
+        assert_eq!(detect(&anon, &master("RU", unrelated)), None);
+        // the token can rule ads out entirely
+        let turbo = token(r#"{"show_ads":false,"hide_ads":true}"#);
+        assert_eq!(detect(&turbo, &master("US", data)), None);
+    }
+
+    #[test]
+    fn detects_stitched_ad_breaks() {
+        let anon = token(r#"{"adblock":false,"show_ads":true,"hide_ads":false}"#);
+        // as served to a viewer in the US, shortened
+        let preroll = Playlist::parse(
+            "#EXTM3U\n\
+            #EXT-X-TWITCH-INFO:NODE=\"video-edge-c9f3a4.iad05\",MANIFEST-NODE-TYPE=\"weaver_cluster\",\
+            SUPPRESS=\"false\",SERVER-TIME=\"1697040000.00\",USER-COUNTRY=\"US\",B=\"false\"\n\
+            #EXT-X-DATERANGE:ID=\"stitched-ad-1697040000-30\",CLASS=\"twitch-stitched-ad\",\
+            START-DATE=\"2023-10-11T16:00:00.000Z\",DURATION=30.000,\
+            X-TV-TWITCH-AD-ROLL-TYPE=\"PREROLL\",X-TV-TWITCH-AD-POD-LENGTH=\"1\"\n\
+            #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"1080p60 (source)\",AUTOSELECT=YES,\
+            DEFAULT=YES\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=8534030,RESOLUTION=1920x1080,CODECS=\"avc1.64002A,mp4a.40.2\",\
+            VIDEO=\"chunked\",FRAME-RATE=60.000\n\
+            https://video-weaver.iad05.hls.ttvnw.net/v1/playlist/abc.m3u8\n",
+        );
+        assert_eq!(detect(&anon, &preroll), Some(AdSign::AdBreak("twitch-stitched-ad".to_owned())));
+        let cue = master("US", "#EXT-X-SCTE35-OUT:DURATION=30.0\n");
+        assert_eq!(detect(&anon, &cue), Some(AdSign::AdBreak("EXT-X-SCTE35-OUT".to_owned())));
+        // other date ranges are fine
+        let other = "#EXT-X-DATERANGE:ID=\"source-1\",CLASS=\"twitch-session\"\n";
+        assert_eq!(detect(&anon, &master("RU", other)), None);
+    }
+}
@@ -0,0 +1,192 @@
+//! Clips. These don't go through usher: the clip access token GQL operation hands back the video
+//! URLs directly, and they only need the token's signature appended. The token is still requested
+//! through the proxy, since clips can be geo-restricted like everything else.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    body::Body,
+    extract::{Path, Query, State},
+    response::IntoResponse,
+};
+use axum_extra::{TypedHeader, headers::UserAgent};
+use http::{HeaderMap, HeaderValue, Response};
+use reqwest_middleware::ClientWithMiddleware as Client;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::error::{AppError, AppResult};
+use crate::pool::Route;
+use crate::{
+    COUNTRY_HEADER, GQLError, LState, PlaybackAccessToken, ProcessData, QueryMap, StreamID,
+    playlist, post_gql, timed,
+};
+
+/// Twitch clips are at most a minute long.
+const MAX_CLIP_SECONDS: u32 = 60;
+
+pub(crate) async fn process_clip(
+    Path(slug): Path<String>,
+    Query(query): QueryMap,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    State(state): State<LState>,
+) -> Response<Body> {
+    clip(slug, None, query, ua, &headers, &state).await
+}
+
+pub(crate) async fn process_clip_in(
+    Path((country, slug)): Path<(String, String)>,
+    Query(query): QueryMap,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    State(state): State<LState>,
+) -> Response<Body> {
+    clip(slug, Some(country), query, ua, &headers, &state).await
+}
+
+async fn clip(
+    slug: String,
+    country: Option<String>,
+    query: HashMap<String, String>,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: &HeaderMap,
+    state: &LState,
+) -> Response<Body> {
+    #[cfg(feature = "metrics")]
+    crate::metrics::request("clip");
+    let pd = match ProcessData::build(slug, country, query, ua, headers, state, StreamID::Clip) {
+        Ok(pd) => pd,
+        Err(e) => return e.into_response(),
+    };
+    process(pd, state).await.into_response()
+}
+
+async fn process(pd: ProcessData, state: &LState) -> AppResult<Response<Body>> {
+    let pd = &pd;
+    let attempt = |route: Arc<Route>| async move {
+        let videos = timed("token", get_videos(&route.client, state, pd)).await?;
+        Ok((videos, route.spec.country.as_ref().map(|c| c.to_ascii_uppercase())))
+    };
+    let (videos, country) = state.failover(pd.country.as_deref(), attempt, |_| false).await?;
+    let mut response = respond(pd, videos)?;
+    if let Some(country) = country {
+        crate::access_log::country(&country);
+        if let Ok(value) = HeaderValue::from_str(&country) {
+            response.headers_mut().insert(COUNTRY_HEADER, value);
+        }
+    }
+    Ok(response)
+}
+
+/// A clip's video in one quality.
+#[derive(Debug, Serialize)]
+struct Video {
+    /// Vertical resolution, like `"1080"`.
+    quality: String,
+    frame_rate: f64,
+    /// Signed, so it can be played from anywhere until the token expires.
+    url: String,
+}
+
+/// Answer with every quality as JSON if `format=json`, otherwise a playlist of the one asked for
+/// with `quality=`, or the best.
+fn respond(pd: &ProcessData, videos: Vec<Video>) -> AppResult<Response<Body>> {
+    if pd.query.get("format").is_some_and(|f| f == "json") {
+        return Ok(Json(json!({ "slug": pd.sid.data(), "videos": videos })).into_response());
+    }
+    let video = match pd.query.get("quality") {
+        Some(quality) => videos.iter().find(|v| &v.quality == quality).ok_or_else(|| {
+            let available: Vec<_> = videos.iter().map(|v| v.quality.as_str()).collect();
+            AppError::BadRequest(format!(
+                "no {quality} quality, available are {}",
+                available.join(", ")
+            ))
+        })?,
+        None => videos.first().ok_or(AppError::ClipNotFound)?,
+    };
+    // the token response doesn't say how long the clip is, so this is an upper bound
+    Ok(playlist(format!(
+        "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:{MAX_CLIP_SECONDS}\n\
+        #EXT-X-PLAYLIST-TYPE:VOD\n#EXTINF:{MAX_CLIP_SECONDS}.000,{} ({}p)\n{}\n#EXT-X-ENDLIST\n",
+        pd.sid.data(),
+        video.quality,
+        video.url
+    )))
+}
+
+/// Get the clip's signed video URLs, best quality first.
+async fn get_videos(client: &Client, state: &LState, pd: &ProcessData) -> AppResult<Vec<Video>> {
+    let request = json!({
+        "operationName": "VideoAccessToken_Clip",
+        "extensions": {
+            "persistedQuery": {
+                "version": 1,
+                "sha256Hash": "36b89d2507fce29e5ca551df756d27c1cfe079e2609642b4390aa4c35796eb11",
+            },
+        },
+        "variables": {
+            "slug": pd.sid.data(),
+            "platform": "web",
+        },
+    });
+
+    let response: ClipResponse = post_gql(client, state, pd, &request).await?;
+    if !response.errors.is_empty() {
+        return Err(AppError::Gql(response.errors.into_iter().map(|e| e.message).collect()));
+    }
+    // null if the slug is wrong or the clip was deleted
+    let clip = response.data.and_then(|d| d.clip).ok_or(AppError::ClipNotFound)?;
+    let token = clip.playback_access_token.ok_or(AppError::ClipNotFound)?;
+    let mut videos = clip
+        .video_qualities
+        .into_iter()
+        .filter_map(|q| {
+            let mut url = Url::parse(&q.source_url).ok()?;
+            url.query_pairs_mut()
+                .append_pair("sig", &token.signature)
+                .append_pair("token", &token.value);
+            Some(Video { quality: q.quality, frame_rate: q.frame_rate, url: url.into() })
+        })
+        .collect::<Vec<_>>();
+    videos.sort_by(|a, b| {
+        let height = |v: &Video| v.quality.parse::<u32>().unwrap_or(0);
+        height(b).cmp(&height(a)).then(b.frame_rate.total_cmp(&a.frame_rate))
+    });
+    Ok(videos)
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipResponse {
+    data: Option<ClipData>,
+    #[serde(default)]
+    errors: Vec<GQLError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClipData {
+    clip: Option<Clip>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Clip {
+    playback_access_token: Option<PlaybackAccessToken>,
+    #[serde(default)]
+    video_qualities: Vec<VideoQuality>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct VideoQuality {
+    quality: String,
+    #[serde(default)]
+    frame_rate: f64,
+    #[serde(rename = "sourceURL")]
+    source_url: String,
+}
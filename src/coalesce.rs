@@ -0,0 +1,165 @@
+//! Sharing master playlist fetches between requests for the same stream. When a popular channel
+//! goes live, everyone asking for it at once waits on a single token and usher round trip rather
+//! than making their own, and the result is kept for a few seconds to absorb the rest of the
+//! burst.
+//!
+//! Requests that share a fetch get the same playlist, so they also share the `play_session_id`
+//! it was fetched with, which Twitch bakes into the signed variant URLs: to Twitch they look like
+//! one viewer. That's the price of the saved round trips. `--playlist-cache-ttl 0` turns sharing
+//! off, giving every request its own session.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::OnceCell;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::error::{AppError, AppResult};
+use crate::{PERMITTED_INCOMING_KEYS, ProcessData, StreamID};
+
+/// What makes two requests want the same playlist from Twitch. The User-Agent isn't part of it,
+/// so a shared fetch is made with whichever one arrived first.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct Key {
+    sid: StreamID,
+    country: Option<String>,
+    /// Only the parameters passed on to usher, sorted.
+    query: Vec<(String, String)>,
+}
+
+impl Key {
+    pub(crate) fn new(pd: &ProcessData) -> Self {
+        let mut query: Vec<_> = pd
+            .query
+            .iter()
+            .filter(|(k, _)| PERMITTED_INCOMING_KEYS.contains(k.as_str()))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        query.sort();
+        Self { sid: pd.sid.clone(), country: pd.country.clone(), query }
+    }
+}
+
+type Outcome<T> = Result<Arc<T>, Arc<AppError>>;
+
+#[derive(Debug)]
+struct Slot<T> {
+    /// Set once the fetch finishes, along with when.
+    done: OnceCell<(Outcome<T>, Instant)>,
+}
+
+impl<T> Slot<T> {
+    /// Whether later requests can still join this one: while the fetch is running, and for `ttl`
+    /// after it succeeded. Failures aren't kept, so the next request tries again.
+    fn joinable(&self, ttl: Duration, now: Instant) -> bool {
+        match self.done.get() {
+            None => true,
+            Some((Ok(_), finished)) => now.duration_since(*finished) < ttl,
+            Some((Err(_), _)) => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Coalescer<T> {
+    ttl: Duration,
+    slots: Mutex<HashMap<Key, Arc<Slot<T>>>>,
+}
+
+impl<T> Coalescer<T> {
+    /// Share fetches, keeping successful ones for `ttl`. Zero disables sharing entirely.
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self { ttl, slots: Mutex::default() }
+    }
+
+    /// Join a fetch for `key` that's running or recently finished, or start one with `fetch`. If
+    /// the request running the fetch goes away, one of those waiting on it takes over.
+    pub(crate) async fn get<F, Fut>(&self, key: Key, fetch: F) -> AppResult<Arc<T>>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = AppResult<T>>,
+    {
+        if self.ttl.is_zero() {
+            return fetch().await.map(Arc::new);
+        }
+        let slot = {
+            let mut slots = self.slots.lock().unwrap();
+            let now = Instant::now();
+            slots.retain(|_, slot| slot.joinable(self.ttl, now));
+            slots.entry(key).or_insert_with(|| Arc::new(Slot { done: OnceCell::new() })).clone()
+        };
+        if slot.done.initialized() {
+            debug!("serving a playlist fetched in the last {:?}", self.ttl);
+        }
+        let (outcome, _) = slot
+            .done
+            .get_or_init(|| async {
+                (fetch().await.map(Arc::new).map_err(Arc::new), Instant::now())
+            })
+            .await;
+        outcome.clone().map_err(|e| e.duplicate())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    use crate::StreamID;
+    use crate::coalesce::{Coalescer, Key};
+    use crate::error::AppError;
+
+    fn key(id: &str) -> Key {
+        Key { sid: StreamID::Live(id.to_owned()), country: None, query: Vec::new() }
+    }
+
+    #[tokio::test]
+    async fn shares_in_flight_and_recent() {
+        let coalescer = Coalescer::new(Duration::from_secs(60));
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok(1)
+        };
+        let (a, b) = tokio::join!(coalescer.get(key("a"), fetch), coalescer.get(key("a"), fetch));
+        assert_eq!((*a.unwrap(), *b.unwrap()), (1, 1));
+        assert_eq!(*coalescer.get(key("a"), fetch).await.unwrap(), 1);
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        coalescer.get(key("b"), fetch).await.unwrap();
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn failures_are_shared_but_not_kept() {
+        let coalescer = Coalescer::<u32>::new(Duration::from_secs(60));
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::Relaxed);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Err(AppError::ChannelOffline)
+        };
+        let (a, b) = tokio::join!(coalescer.get(key("a"), fetch), coalescer.get(key("a"), fetch));
+        assert_eq!(fetches.load(Ordering::Relaxed), 1);
+        assert_eq!(a.unwrap_err().kind(), "channel_offline");
+        assert_eq!(b.unwrap_err().kind(), "channel_offline");
+        assert!(coalescer.get(key("a"), fetch).await.is_err());
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_never_shares() {
+        let coalescer = Coalescer::new(Duration::ZERO);
+        let fetches = AtomicUsize::new(0);
+        let fetch = || async {
+            fetches.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        };
+        let _ = tokio::join!(coalescer.get(key("a"), fetch), coalescer.get(key("a"), fetch));
+        assert_eq!(fetches.load(Ordering::Relaxed), 2);
+    }
+}
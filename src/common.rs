@@ -2,11 +2,31 @@ use anyhow::{Context, Result};
 use axum_extra::TypedHeader;
 use axum_extra::headers::{Header, UserAgent};
 use http::HeaderValue;
+use url::Url;
 
 // use ESR user-agent if we don't have anything else
 pub(crate) const USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:140.0) \
 Gecko/20100101 Firefox/140.0";
 
+/// Where Twitch's services are. Overridable so the server can be pointed at mirrors or mocks.
+#[derive(Clone, Debug)]
+pub(crate) struct Upstreams {
+    /// GQL endpoint itself, not a base.
+    pub(crate) gql: Url,
+    /// Base URL that playlist paths are joined onto.
+    pub(crate) usher: Url,
+}
+
+/// Parse a base URL, making sure it ends in a slash so that joining paths onto it appends to it
+/// rather than replacing the last segment.
+pub(crate) fn parse_base_url(input: &str) -> Result<Url> {
+    let mut url = Url::parse(input)?;
+    if !url.path().ends_with('/') {
+        url.set_path(&format!("{}/", url.path()));
+    }
+    Ok(url)
+}
+
 pub(crate) fn get_user_agent(
     ua: Option<TypedHeader<UserAgent>>, // inbound user UA
     ua_override: Option<&HeaderValue>,
@@ -0,0 +1,235 @@
+//! Options from a TOML config file, and reloading them on SIGHUP. The file's keys are the long
+//! option names; its values are turned into arguments, so they're validated exactly like the
+//! command line. Anything set on the command line or in the environment wins over the file.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result, bail};
+use clap::{ArgAction, ArgMatches, CommandFactory, Parser, parser::ValueSource};
+use toml::Value;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::{Registry, filter::LevelFilter, prelude::*, reload};
+
+use crate::Opts;
+#[cfg(unix)]
+use crate::{ClientSettings, LState, custom_routes, pool::Source};
+
+pub(crate) type LogLevel = reload::Handle<LevelFilter, Registry>;
+
+/// Set up logging, returning a handle to change the level later. Subcommands log to stderr, as
+/// their output goes to stdout.
+pub(crate) fn init_logging(debug: bool, stderr: bool) -> LogLevel {
+    let (filter, handle) = reload::Layer::new(level(debug));
+    let writer =
+        if stderr { BoxMakeWriter::new(io::stderr) } else { BoxMakeWriter::new(io::stdout) };
+    let format = tracing_subscriber::fmt::layer().with_writer(writer);
+    tracing_subscriber::registry().with(filter).with(format).init();
+    handle
+}
+
+fn level(debug: bool) -> LevelFilter {
+    if debug { LevelFilter::DEBUG } else { LevelFilter::INFO }
+}
+
+/// Parse options from `args`, the environment, and the config file if one is given.
+pub(crate) fn load<I, T>(args: I) -> Result<Opts>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString>,
+{
+    let mut args: Vec<OsString> = args.into_iter().map(Into::into).collect();
+    // this pass only finds the file and what's already set; the real parse reports any errors
+    if let Ok(matches) = Opts::command().ignore_errors(true).try_get_matches_from(&args)
+        && let Some(path) = matches.get_one::<PathBuf>("config")
+    {
+        let text =
+            fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let table: toml::Table =
+            toml::from_str(&text).with_context(|| format!("parsing {}", path.display()))?;
+        let file = file_args(&matches, table).with_context(|| format!("in {}", path.display()))?;
+        // right after the program name, since anything after a subcommand is taken as its own
+        let at = args.len().min(1);
+        args.splice(at..at, file);
+    }
+    Ok(Opts::try_parse_from(args)?)
+}
+
+/// Arguments equivalent to the file's settings, skipping those already set elsewhere.
+fn file_args(matches: &ArgMatches, table: toml::Table) -> Result<Vec<OsString>> {
+    let command = Opts::command();
+    let mut args = vec![];
+    for (key, value) in table {
+        let id = key.replace('-', "_");
+        let Some(arg) =
+            command.get_arguments().find(|a| a.get_id() == id.as_str() && id != "config")
+        else {
+            bail!("unknown option `{key}`");
+        };
+        let Some(long) = arg.get_long() else {
+            bail!("`{key}` can't be set from a config file");
+        };
+        if matches!(
+            matches.value_source(&id),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            debug!("ignoring `{key}` from config file, it's set elsewhere");
+            continue;
+        }
+        match (arg.get_action(), value) {
+            (ArgAction::SetTrue, Value::Boolean(set)) => {
+                if set {
+                    args.push(format!("--{long}").into());
+                }
+            }
+            (ArgAction::SetTrue, _) => bail!("`{key}` must be true or false"),
+            (ArgAction::Append, Value::Array(values)) => {
+                for value in values {
+                    args.push(format!("--{long}={}", scalar(&key, value)?).into());
+                }
+            }
+            (ArgAction::Set | ArgAction::Append, value) => {
+                args.push(format!("--{long}={}", scalar(&key, value)?).into());
+            }
+            _ => bail!("`{key}` can't be set from a config file"),
+        }
+    }
+    Ok(args)
+}
+
+fn scalar(key: &str, value: Value) -> Result<String> {
+    Ok(match value {
+        Value::String(s) => s,
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Datetime(d) => d.to_string(),
+        Value::Array(_) | Value::Table(_) => bail!("`{key}` takes a single value"),
+    })
+}
+
+/// Endlessly waits for SIGHUP, then re-reads the options and applies them.
+#[cfg(unix)]
+pub(crate) async fn reload_on_sighup(mut opts: Opts, state: LState, log_level: LogLevel) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("can't listen for SIGHUP, config reloading disabled: {e}");
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("SIGHUP received, reloading configuration");
+        match load(std::env::args_os()) {
+            Ok(new) => {
+                apply(&opts, &new, &state, &log_level);
+                opts = new;
+            }
+            Err(e) => error!("configuration is invalid, not reloading: {e:#}"),
+        }
+    }
+}
+
+/// Apply what can be changed while running: log level, rate limits, upstream timeouts and
+/// retries, and the custom proxy list. Everything else needs a restart.
+#[cfg(unix)]
+fn apply(old: &Opts, new: &Opts, state: &LState, log_level: &LogLevel) {
+    if let Err(e) = log_level.reload(level(new.debug)) {
+        error!("failed to change log level: {e}");
+    }
+    state.limiter.reconfigure(new.rate_limit, new.rate_limit_burst, new.trusted_proxy.clone());
+    if let Err(e) = state.pool.set_client_settings(ClientSettings::from(new)) {
+        error!("failed to apply new timeouts: {e:#}");
+    }
+    let proxies_reloadable = old.uses_custom_proxies() && new.uses_custom_proxies();
+    let overridden = state.pool.all().iter().any(|r| r.spec.source == Source::Admin);
+    if proxies_reloadable && overridden {
+        info!("not reloading proxies, since they were set with the admin endpoint");
+    } else if proxies_reloadable {
+        let replaced = custom_routes(new)
+            .and_then(|specs| state.pool.replace(|spec| spec.source == Source::Custom, specs));
+        if let Err(e) = replaced {
+            error!("failed to reload proxies: {e:#}");
+        }
+    }
+
+    let mut rest = new.clone();
+    rest.debug = old.debug;
+    rest.rate_limit = old.rate_limit;
+    rest.rate_limit_burst = old.rate_limit_burst;
+    rest.trusted_proxy = old.trusted_proxy.clone();
+    rest.upstream_timeout = old.upstream_timeout;
+    rest.retry_min_delay_ms = old.retry_min_delay_ms;
+    rest.retry_max_delay_ms = old.retry_max_delay_ms;
+    rest.retry_duration = old.retry_duration;
+    if proxies_reloadable {
+        rest.proxy = old.proxy.clone();
+        rest.proxy_list = old.proxy_list.clone();
+    }
+    if rest != *old {
+        warn!("some changed settings only take effect after a restart");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::config::load;
+
+    /// Write `contents` to a temporary config file and load it along with `args`.
+    fn load_with(name: &str, contents: &str, args: &[&str]) -> anyhow::Result<crate::Opts> {
+        let path =
+            std::env::temp_dir().join(format!("luminous-ttv-{}-{name}.toml", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        let mut argv = vec!["luminous-ttv", "--config", path.to_str().unwrap()];
+        #[cfg(feature = "true-status")]
+        argv.extend(["--status-secret", "secret"]);
+        argv.extend(args);
+        let opts = load(argv);
+        fs::remove_file(path).unwrap();
+        opts
+    }
+
+    #[test]
+    fn file_fills_in_unset_options() {
+        let file = r#"
+            proxy = ["http://10.0.0.1:1", "http://10.0.0.2:2"]
+            rate-limit = 5
+            upstream_timeout = 3
+            debug = true
+        "#;
+        let opts = load_with("fill", file, &["--rate-limit", "7"]).unwrap();
+        assert_eq!(opts.proxy.len(), 2);
+        assert_eq!(opts.rate_limit, 7); // command line wins
+        assert_eq!(opts.upstream_timeout, 3);
+        assert!(opts.debug);
+        assert_eq!(opts.concurrency_limit, 64);
+    }
+
+    #[test]
+    fn bad_files_are_rejected() {
+        let args = ["--no-proxy"];
+        assert!(load_with("unknown", "no-such-option = 1", &args).is_err());
+        assert!(load_with("type", "debug = \"yes\"", &args).is_err());
+        assert!(load_with("value", "rate-limit = -1", &args).is_err());
+        assert!(load_with("array", "rate-limit = [1, 2]", &args).is_err());
+    }
+
+    #[test]
+    fn file_with_subcommand() {
+        let file = "rate-limit = 5\nno-proxy = true";
+        let opts = load_with("subcommand", file, &["fetch", "--best", "live", "foo"]).unwrap();
+        assert_eq!(opts.rate_limit, 5);
+        assert!(matches!(opts.command, Some(crate::Command::Fetch(_))));
+        // only the server needs a status secret
+        let opts = load(["luminous-ttv", "--no-proxy", "fetch", "vod", "123"]).unwrap();
+        assert!(matches!(opts.command, Some(crate::Command::Fetch(_))));
+    }
+}
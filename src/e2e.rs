@@ -0,0 +1,1092 @@
+//! End-to-end tests. The real router runs in-process against local stand-ins for Twitch (GQL and
+//! usher) and Hola, with every upstream request going through a local HTTP proxy so that proxy
+//! routing is exercised too.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::{
+    Json, Router,
+    body::{Body, Bytes},
+    extract::connect_info::MockConnectInfo,
+    extract::{Path, Request, State},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use clap::Parser;
+use http::{HeaderMap, Method, StatusCode, Uri, header};
+use serde_json::{Value, json};
+use tower::ServiceExt;
+use url::Url;
+
+use crate::{LState, Opts, router, start_proxies};
+
+const CLIENT_ID: &str = "kimne78kx3ncx6brgo4mv6wki5h1ko";
+const SIGNATURE: &str = "0123456789abcdef0123456789abcdef01234567";
+
+const MASTER: &str = r#"#EXTM3U
+#EXT-X-TWITCH-INFO:NODE="video-edge-c2a7b4.arn03",MANIFEST-NODE-TYPE="weaver_cluster",MANIFEST-NODE="video-weaver.arn03",SUPPRESS="false",SERVER-TIME="1700000000.00",TRANSCODESTACK="2023-Transcode-QS-V1",USER-IP="203.0.113.7",SERVING-ID="abc123",CLUSTER="arn03",ABS="false",VIDEO-SESSION-ID="1234567890",BROADCAST-ID="40000000000",STREAM-TIME="3600.00",B="false",USER-COUNTRY="RU",MANIFEST-CLUSTER="arn03",ORIGIN="fra05",C="aHR0cHM6Ly9leGFtcGxlLmNvbQ==",D="false"
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="chunked",NAME="1080p60 (source)",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=8000000,RESOLUTION=1920x1080,CODECS="avc1.64002A,mp4a.40.2",VIDEO="chunked",FRAME-RATE=60.000
+https://video-weaver.arn03.hls.ttvnw.net/v1/playlist/source.m3u8
+#EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID="720p60",NAME="720p60",AUTOSELECT=YES,DEFAULT=YES
+#EXT-X-STREAM-INF:BANDWIDTH=3422999,RESOLUTION=1280x720,CODECS="avc1.4D401F,mp4a.40.2",VIDEO="720p60",FRAME-RATE=60.000
+https://video-weaver.arn03.hls.ttvnw.net/v1/playlist/720p60.m3u8
+"#;
+
+/// A request one of the stand-ins received.
+#[derive(Clone, Debug)]
+struct Seen {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl Seen {
+    fn query(&self, key: &str) -> Option<String> {
+        let query = self.uri.query().unwrap_or_default();
+        url::form_urlencoded::parse(query.as_bytes())
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.into_owned())
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|v| v.to_str().unwrap())
+    }
+
+    fn json(&self) -> Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct Log(Arc<Mutex<Vec<Seen>>>);
+
+impl Log {
+    async fn record(&self, req: Request) -> Seen {
+        let (parts, body) = req.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        let seen = Seen { method: parts.method, uri: parts.uri, headers: parts.headers, body };
+        self.0.lock().unwrap().push(seen.clone());
+        seen
+    }
+
+    fn all(&self) -> Vec<Seen> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn paths(&self) -> Vec<String> {
+        self.all().iter().map(|s| s.uri.path().to_owned()).collect()
+    }
+}
+
+async fn serve(router: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+    addr
+}
+
+fn twitch(log: Log) -> Router {
+    Router::new()
+        .route("/gql", post(gql))
+        .route("/api/channel/hls/{file}", get(usher))
+        .route("/vod/{file}", get(usher))
+        .route("/v1/playlist/{file}", get(media))
+        .with_state(log)
+}
+
+async fn gql(State(log): State<Log>, req: Request) -> Response {
+    let body = log.record(req).await.json();
+    let vars = &body["variables"];
+    if vars["vodID"] == "404" {
+        // what Twitch sends for deleted VODs
+        return Json(json!({"data": {"videoPlaybackAccessToken": null}})).into_response();
+    }
+    let expires = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() + 20 * 60;
+    if body["operationName"] == "VideoAccessToken_Clip" {
+        return clip(vars["slug"].as_str().unwrap(), expires);
+    }
+    if body["operationName"] == "FeaturedContentCarouselStreams" {
+        let stream = json!({"broadcaster": {"login": "abc"}, "type": "live"});
+        return Json(json!({"data": {"featuredStreams": [{"stream": stream}]}})).into_response();
+    }
+    let (key, value) = if vars["isLive"] == true {
+        ("streamPlaybackAccessToken", json!({"channel": vars["login"], "expires": expires}))
+    } else {
+        ("videoPlaybackAccessToken", json!({"vod_id": vars["vodID"], "expires": expires}))
+    };
+    Json(json!({
+        "data": {
+            key: {
+                "value": value.to_string(),
+                "signature": SIGNATURE,
+                "__typename": "PlaybackAccessToken",
+            },
+        },
+        "extensions": {"durationMilliseconds": 5, "operationName": "PlaybackAccessToken"},
+    }))
+    .into_response()
+}
+
+fn clip(slug: &str, expires: u64) -> Response {
+    if slug == "missing" {
+        return Json(json!({"data": {"clip": null}})).into_response();
+    }
+    let quality = |quality: &str, frame_rate: u32| {
+        json!({
+            "quality": quality,
+            "frameRate": frame_rate,
+            "sourceURL": format!("https://production.assets.clips.twitchcdn.net/{slug}-{quality}.mp4"),
+        })
+    };
+    Json(json!({
+        "data": {
+            "clip": {
+                "playbackAccessToken": {
+                    "value": json!({"clip_uri": "", "expires": expires}).to_string(),
+                    "signature": SIGNATURE,
+                },
+                "videoQualities": [quality("480", 30), quality("1080", 60), quality("720", 60)],
+            },
+        },
+    }))
+    .into_response()
+}
+
+async fn usher(State(log): State<Log>, Path(file): Path<String>, req: Request) -> Response {
+    let seen = log.record(req).await;
+    if file == "offline.m3u8" {
+        let body = json!([{
+            "url": "https://usher.ttvnw.net/api/channel/hls/offline.m3u8?token=secret",
+            "error": "twirp error not_found: transcode does not exist",
+            "error_code": "transcode_does_not_exist",
+            "type": "error",
+        }]);
+        return (StatusCode::NOT_FOUND, Json(body)).into_response();
+    }
+    if file == "geoblocked.m3u8" {
+        let body = json!([{
+            "error": "Content is restricted in your region",
+            "error_code": "content_geoblocked",
+            "type": "error",
+        }]);
+        return (StatusCode::FORBIDDEN, Json(body)).into_response();
+    }
+    let master = if file == "preroll.m3u8" {
+        let data = "#EXT-X-SESSION-DATA:DATA-ID=\"com.twitch.ads.preroll\",VALUE=\"1\"\n";
+        MASTER.replacen("#EXTM3U\n", &format!("#EXTM3U\n{data}"), 1)
+    } else if file == "stitched.m3u8" {
+        let ad = "#EXT-X-DATERANGE:ID=\"stitched-ad-1-30\",CLASS=\"twitch-stitched-ad\",\
+            START-DATE=\"2023-10-11T16:00:00.000Z\",DURATION=30.000,X-TV-TWITCH-AD-ROLL-TYPE=\"PREROLL\"\n";
+        MASTER.replacen("#EXTM3U\n", &format!("#EXTM3U\n{ad}"), 1)
+    } else if file == "nowhere.m3u8" {
+        MASTER.replace(r#",USER-COUNTRY="RU""#, "")
+    } else if file == "abroad.m3u8" {
+        MASTER.replace(r#"USER-COUNTRY="RU""#, r#"USER-COUNTRY="US""#) // Twitch serves ads there
+    } else if file == "local.m3u8" {
+        // variants served by this stand-in, for HLS proxy mode
+        let host = seen.header("host").unwrap();
+        MASTER.replace("https://video-weaver.arn03.hls.ttvnw.net", &format!("http://{host}"))
+    } else {
+        MASTER.to_owned()
+    };
+    ([(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")], master).into_response()
+}
+
+async fn media(State(log): State<Log>, Path(file): Path<String>, req: Request) -> Response {
+    let seen = log.record(req).await;
+    if let Some(ext) = file.strip_prefix("moved.") {
+        // somewhere that would work, to show redirects aren't followed
+        let location = format!("/v1/playlist/seg0.{ext}");
+        return (StatusCode::FOUND, [(header::LOCATION, location)]).into_response();
+    }
+    if file.ends_with(".ts") {
+        return segment(seen.header("range"));
+    }
+    let body = "#EXTM3U\n#EXT-X-TARGETDURATION:2\n#EXT-X-MEDIA-SEQUENCE:10\n\
+        #EXT-X-DATERANGE:ID=\"stitched-ad-1\",CLASS=\"twitch-stitched-ad\",DURATION=2.000\n\
+        #EXT-X-DISCONTINUITY\n#EXTINF:2.000,Amazon|1\nad0.ts\n\
+        #EXT-X-DISCONTINUITY\n#EXTINF:2.000,live\nseg0.ts\n";
+    ([(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")], body).into_response()
+}
+
+/// Ten bytes of "video", honoring a single `bytes=a-b` range like a CDN would.
+fn segment(range: Option<&str>) -> Response {
+    const VIDEO: &[u8] = b"0123456789";
+    let Some((start, end)) = range
+        .and_then(|r| r.strip_prefix("bytes="))
+        .and_then(|r| r.split_once('-'))
+        .and_then(|(a, b)| Some((a.parse::<usize>().ok()?, b.parse::<usize>().ok()?)))
+    else {
+        return ([(header::CONTENT_TYPE, "video/mp2t")], VIDEO).into_response();
+    };
+    let headers = [
+        (header::CONTENT_TYPE, "video/mp2t".to_owned()),
+        (header::CONTENT_RANGE, format!("bytes {start}-{end}/{}", VIDEO.len())),
+    ];
+    (StatusCode::PARTIAL_CONTENT, headers, &VIDEO[start..=end]).into_response()
+}
+
+#[cfg(feature = "hola")]
+fn hola(log: Log, proxy: SocketAddr) -> Router {
+    async fn bg_init(State((log, _)): State<(Log, SocketAddr)>, req: Request) -> Json<Value> {
+        log.record(req).await;
+        Json(json!({"ver": "1.2.3", "key": 12345, "country": "RU"}))
+    }
+    async fn tunnels(State((log, proxy)): State<(Log, SocketAddr)>, req: Request) -> Response {
+        if log.record(req).await.query("country").as_deref() == Some("zz") {
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response(); // an outage, say
+        }
+        let port = json!({
+            "direct": proxy.port(), "hola": 0, "peer": 0, "trial": 0, "trial_peer": 0,
+        });
+        Json(json!({
+            "agent_key": "agentkey",
+            "agent_types": {},
+            "ip_list": {"": proxy.ip().to_string()}, // no hostname means plain HTTP
+            "port": port,
+            "protocol": {},
+            "vendor": {},
+            "ztun": {},
+        }))
+        .into_response()
+    }
+    Router::new()
+        .route("/background_init", post(bg_init))
+        .route("/zgettunnels", get(tunnels))
+        .route("/vpn_countries.json", get(|| async { Json(json!(["RU", "UA"])) }))
+        .with_state((log, proxy))
+}
+
+/// A forward proxy for plain HTTP: requests arrive with absolute URIs and are sent on as-is.
+fn proxy(log: Log) -> Router {
+    async fn forward(State(log): State<Log>, req: Request) -> Response {
+        let seen = log.record(req).await;
+        let client = reqwest::Client::builder()
+            .no_proxy()
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let mut rb = client.request(seen.method.clone(), seen.uri.to_string());
+        for (name, value) in &seen.headers {
+            if name != header::PROXY_AUTHORIZATION && name != header::HOST {
+                rb = rb.header(name, value);
+            }
+        }
+        let upstream = rb.body(seen.body).send().await.unwrap();
+        let mut response = Response::builder().status(upstream.status());
+        for name in [header::CONTENT_TYPE, header::LOCATION] {
+            if let Some(value) = upstream.headers().get(&name) {
+                response = response.header(name, value);
+            }
+        }
+        response.body(Body::from(upstream.bytes().await.unwrap())).unwrap()
+    }
+    Router::new().fallback(forward).with_state(log)
+}
+
+struct Harness {
+    opts: Opts,
+    app: Router,
+    twitch: Log,
+    proxy: Log,
+    #[cfg(feature = "hola")]
+    hola: Log,
+}
+
+impl Harness {
+    /// Everything goes through one `--proxy`.
+    async fn new(extra_args: &[&str]) -> Self {
+        Self::with_args(&["--proxy", "{proxy}"], extra_args).await
+    }
+
+    /// `{proxy}`, `{twitch}` and `{hola}` in arguments are replaced with the stand-ins' addresses.
+    async fn with_args(args: &[&str], extra_args: &[&str]) -> Self {
+        let (twitch_log, proxy_log) = (Log::default(), Log::default());
+        let twitch_addr = serve(twitch(twitch_log.clone())).await;
+        let proxy_addr = serve(proxy(proxy_log.clone())).await;
+        #[cfg(feature = "hola")]
+        let hola_log = Log::default();
+        #[cfg(feature = "hola")]
+        let hola_addr = serve(hola(hola_log.clone(), proxy_addr)).await.to_string();
+        #[cfg(not(feature = "hola"))]
+        let hola_addr = String::new();
+        let base = [
+            "luminous-ttv",
+            "--gql-url",
+            "http://{twitch}/gql",
+            "--usher-url",
+            "http://{twitch}/",
+            "--health-check-interval",
+            "0",
+            #[cfg(feature = "hola")]
+            "--hola-url",
+            #[cfg(feature = "hola")]
+            "http://{hola}/",
+            #[cfg(feature = "hola")]
+            "--hola-refresh-interval",
+            #[cfg(feature = "hola")]
+            "0",
+            #[cfg(feature = "true-status")]
+            "--status-secret",
+            #[cfg(feature = "true-status")]
+            "secret",
+        ];
+        let argv = base.iter().chain(args).chain(extra_args).map(|arg| {
+            arg.replace("{proxy}", &format!("http://{proxy_addr}"))
+                .replace("{twitch}", &twitch_addr.to_string())
+                .replace("{hola}", &hola_addr)
+        });
+        let opts = Opts::try_parse_from(argv).unwrap();
+        let proxies = start_proxies(&opts).await.unwrap();
+        let app = router(LState::new(&opts, proxies).unwrap(), &opts)
+            .layer(MockConnectInfo(SocketAddr::from(([192, 0, 2, 1], 40000))));
+        Self {
+            opts: opts.clone(),
+            app,
+            twitch: twitch_log,
+            proxy: proxy_log,
+            #[cfg(feature = "hola")]
+            hola: hola_log,
+        }
+    }
+
+    async fn get(&self, uri: &str) -> (StatusCode, HeaderMap, String) {
+        self.get_with(uri, &[]).await
+    }
+
+    async fn get_with(
+        &self,
+        uri: &str,
+        headers: &[(header::HeaderName, &str)],
+    ) -> (StatusCode, HeaderMap, String) {
+        self.send(Method::GET, uri, headers).await
+    }
+
+    async fn post(&self, uri: &str) -> (StatusCode, HeaderMap, String) {
+        self.send(Method::POST, uri, &[]).await
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        uri: &str,
+        headers: &[(header::HeaderName, &str)],
+    ) -> (StatusCode, HeaderMap, String) {
+        let mut req = http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::USER_AGENT, "TestAgent/1.0")
+            .header(header::ORIGIN, "https://www.twitch.tv");
+        for (name, value) in headers {
+            req = req.header(name, *value);
+        }
+        let req = req.body(Body::empty()).unwrap();
+        let res = self.app.clone().oneshot(req).await.unwrap();
+        let (parts, body) = res.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX).await.unwrap();
+        (parts.status, parts.headers, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn gql(&self) -> Vec<Seen> {
+        self.twitch.all().into_iter().filter(|s| s.uri.path() == "/gql").collect()
+    }
+
+    fn usher(&self) -> Vec<Seen> {
+        self.twitch.all().into_iter().filter(|s| s.uri.path() != "/gql").collect()
+    }
+}
+
+fn is_id(s: &str) -> bool {
+    s.len() == 32 && s.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+#[tokio::test]
+async fn live_playlist() {
+    let h = Harness::new(&[]).await;
+    let (status, headers, body) = h
+        .get("/live/AbC?player_backend=mediaplayer&allow_source=true&browser_family=firefox")
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "application/vnd.apple.mpegurl");
+    assert_eq!(headers[header::CACHE_CONTROL], "no-cache, no-store");
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert!(body.starts_with("#EXTM3U"));
+    assert!(body.contains("USER-COUNTRY=\"RU\""));
+    assert_eq!(headers["x-luminous-country"], "RU");
+    if cfg!(feature = "redact-ip") {
+        assert!(body.contains("USER-IP=\"1.1.1.1\"") && !body.contains("203.0.113.7"));
+    } else {
+        assert!(body.contains("USER-IP=\"203.0.113.7\""));
+    }
+
+    let gql = h.gql();
+    assert_eq!(gql.len(), 1);
+    assert_eq!(gql[0].method, Method::POST);
+    assert_eq!(gql[0].header("client-id"), Some(CLIENT_ID));
+    assert!(is_id(gql[0].header("device-id").unwrap()));
+    assert_eq!(gql[0].header("user-agent"), Some("TestAgent/1.0"));
+    let request = gql[0].json();
+    assert_eq!(request["operationName"], "PlaybackAccessToken");
+    assert_eq!(request["variables"]["login"], "abc");
+    assert_eq!(request["variables"]["isLive"], true);
+    assert_eq!(request["variables"]["isVod"], false);
+
+    let usher = h.usher();
+    assert_eq!(usher.len(), 1);
+    let usher = &usher[0];
+    assert_eq!(usher.uri.path(), "/api/channel/hls/abc.m3u8");
+    assert_eq!(usher.header("user-agent"), Some("TestAgent/1.0"));
+    assert_eq!(usher.query("player_backend").as_deref(), Some("mediaplayer"));
+    assert_eq!(usher.query("allow_source").as_deref(), Some("true"));
+    assert_eq!(usher.query("browser_family"), None); // not on the permitted list
+    assert!(usher.query("p").unwrap().parse::<u32>().is_ok());
+    let session = usher.query("play_session_id").unwrap();
+    assert!(is_id(&session) && session == session.to_ascii_lowercase());
+    let token: Value = serde_json::from_str(&usher.query("token").unwrap()).unwrap();
+    assert_eq!(token["channel"], "abc");
+    assert_eq!(usher.query("sig").as_deref(), Some(SIGNATURE));
+    assert_eq!(usher.query("acmb").as_deref(), Some("e30="));
+
+    // both upstream requests went through the proxy, with absolute URIs
+    let proxied = h.proxy.all();
+    assert_eq!(proxied.len(), 2);
+    assert!(proxied.iter().all(|s| s.uri.scheme_str() == Some("http")));
+    assert_eq!(h.proxy.paths(), ["/gql", "/api/channel/hls/abc.m3u8"]);
+}
+
+#[tokio::test]
+async fn token_reused() {
+    let h = Harness::new(&["--playlist-cache-ttl", "0"]).await;
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    assert_eq!(h.gql().len(), 1);
+    assert_eq!(h.usher().len(), 2);
+    let (_, _, body) = h.get("/stat/").await;
+    let status: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(status["token_cache"]["hits"], 1);
+}
+
+#[tokio::test]
+async fn vod_playlist() {
+    let h = Harness::new(&[]).await;
+    let (status, _, body) = h.get("/vod/123456?allow_source=true").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(body.starts_with("#EXTM3U"));
+    let request = h.gql()[0].json();
+    assert_eq!(request["variables"]["isVod"], true);
+    assert_eq!(request["variables"]["vodID"], "123456");
+    assert_eq!(request["variables"]["login"], "");
+    let usher = &h.usher()[0];
+    assert_eq!(usher.uri.path(), "/vod/123456.m3u8");
+    assert_eq!(usher.query("allow_source").as_deref(), Some("true"));
+}
+
+#[tokio::test]
+async fn clip_urls() {
+    let h = Harness::new(&[]).await;
+    let (status, headers, body) = h.get("/clip/FunnyClip-AbC").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers[header::CONTENT_TYPE], "application/vnd.apple.mpegurl");
+    assert!(body.contains("FunnyClip-AbC-1080.mp4?sig="), "{body}");
+    assert!(body.contains("#EXT-X-PLAYLIST-TYPE:VOD\n"), "{body}");
+    assert!(body.ends_with("#EXT-X-ENDLIST\n"), "{body}");
+    let request = h.gql()[0].json();
+    assert_eq!(request["operationName"], "VideoAccessToken_Clip");
+    assert_eq!(request["variables"]["slug"], "FunnyClip-AbC"); // slugs are case-sensitive
+    assert_eq!(h.proxy.paths(), ["/gql"]);
+
+    let (_, _, body) = h.get("/clip/FunnyClip-AbC?quality=720").await;
+    assert!(body.contains("-720.mp4?sig="), "{body}");
+    let (_, _, body) = h.get("/clip/FunnyClip-AbC?format=json").await;
+    let videos: Value = serde_json::from_str(&body).unwrap();
+    let qualities: Vec<_> =
+        videos["videos"].as_array().unwrap().iter().map(|v| &v["quality"]).collect();
+    assert_eq!(qualities, ["1080", "720", "480"]);
+    let url = Url::parse(videos["videos"][0]["url"].as_str().unwrap()).unwrap();
+    assert!(url.query_pairs().any(|(k, v)| k == "sig" && v == SIGNATURE));
+    assert!(url.query_pairs().any(|(k, _)| k == "token"));
+    assert!(h.usher().is_empty());
+
+    let (status, _, body) = h.get("/clip/missing").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "clip_not_found");
+    assert_eq!(h.get("/clip/FunnyClip-AbC?quality=4k").await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn playlists_shared() {
+    let h = Harness::new(&[]).await;
+    let (a, b) = tokio::join!(h.get("/live/abc"), h.get("/live/ABC?max_resolution=720"));
+    assert_eq!((a.0, b.0), (StatusCode::OK, StatusCode::OK));
+    assert_eq!((h.gql().len(), h.usher().len()), (1, 1));
+    // filtered for each request
+    assert!(a.2.contains("source.m3u8") && !b.2.contains("source.m3u8"));
+    assert_eq!(b.1["x-luminous-country"], "RU");
+
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    assert_eq!(h.usher().len(), 1);
+    // usher would see something different
+    assert_eq!(h.get("/live/abc?platform=android").await.0, StatusCode::OK);
+    assert_eq!(h.get("/live/def").await.0, StatusCode::OK);
+    assert_eq!(h.usher().len(), 3);
+}
+
+#[tokio::test]
+async fn access_log() {
+    let path = std::env::temp_dir().join(format!("luminous-ttv-{}-access.log", std::process::id()));
+    let h = Harness::new(&["--access-log", path.to_str().unwrap()]).await;
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    assert_eq!(h.get("/vod/404").await.0, StatusCode::NOT_FOUND);
+    assert_eq!(h.get("/stat/").await.0, StatusCode::OK); // not logged
+    let mut log = String::new();
+    for _ in 0..50 {
+        log = std::fs::read_to_string(&path).unwrap_or_default();
+        if log.lines().count() >= 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    std::fs::remove_file(&path).unwrap();
+    let entries: Vec<Value> = log.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(entries.len(), 2, "{log}");
+    let live = &entries[0];
+    assert_eq!(live["route"], "/live/{id}");
+    assert_eq!(live["sid"], "live/abc");
+    assert_eq!((live["status"].as_u64(), live["country"].as_str()), (Some(200), Some("RU")));
+    assert!(live["via"].as_str().unwrap().starts_with("http://127.0.0.1:"));
+    let stages: Vec<_> = live["stages"].as_array().unwrap().iter().map(|s| &s["stage"]).collect();
+    assert_eq!(stages, ["token", "m3u8"]);
+    assert_eq!(live["client"].as_str().unwrap().len(), 16);
+    assert_eq!(entries[1]["error"], "vod_not_found");
+    // the mock client is 192.0.2.1
+    assert!(!log.contains("192.0.2") && !log.contains(SIGNATURE) && !log.contains("token\":"));
+}
+
+#[tokio::test]
+async fn variants_limited() {
+    let args =
+        ["--max-resolution", "1080", "--prefer-resolution", "720", "--playlist-cache-ttl", "0"];
+    let h = Harness::new(&args).await;
+    let (_, _, body) = h.get("/live/abc").await;
+    let variants: Vec<_> = body.lines().filter(|l| l.ends_with(".m3u8")).collect();
+    assert_eq!(variants.len(), 2);
+    assert!(variants[0].ends_with("/720p60.m3u8"), "{body}");
+    let (_, _, body) = h.get("/live/abc?max_resolution=720p").await;
+    assert!(!body.contains("source.m3u8") && !body.contains("GROUP-ID=\"chunked\""), "{body}");
+    assert!(body.contains("USER-COUNTRY=\"RU\""));
+    // they're for this server, not Twitch
+    assert_eq!(h.usher()[1].query("max_resolution"), None);
+    assert_eq!(h.get("/live/abc?max_bitrate=lots").await.0, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn rate_limited() {
+    let h = Harness::new(&["--rate-limit", "1", "--rate-limit-burst", "1"]).await;
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    let (status, headers, body) = h.get("/live/abc").await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(headers[header::RETRY_AFTER], "60");
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "rate_limited");
+    // status endpoints aren't limited
+    assert_eq!(h.get("/ping").await.0, StatusCode::OK);
+    assert_eq!(h.gql().len(), 1);
+}
+
+#[tokio::test]
+async fn media_playlists_not_rate_limited() {
+    let args =
+        ["--hls-proxy", "--hls-allowed-host", "127.0.0.1", "--public-url", "https://ttv.example/"];
+    let h = Harness::new(&[&args[..], &["--rate-limit", "1", "--rate-limit-burst", "1"]].concat())
+        .await;
+    let (status, _, master) = h.get("/live/local").await;
+    assert_eq!(status, StatusCode::OK);
+    let variant = master.lines().find(|l| !l.starts_with('#')).unwrap();
+    let variant = variant.strip_prefix("https://ttv.example").unwrap();
+    // refreshed like a player would, after the client's playlist limit is used up
+    for _ in 0..3 {
+        assert_eq!(h.get(variant).await.0, StatusCode::OK);
+    }
+    assert_eq!(h.get("/live/local").await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn proxy_errors_fail_over() {
+    // a plain HTTP proxy that can't reach anything answers for Twitch
+    async fn broken(State(log): State<Log>, req: Request) -> StatusCode {
+        log.record(req).await;
+        StatusCode::BAD_GATEWAY
+    }
+    let log = Log::default();
+    let broken = serve(Router::new().fallback(broken).with_state(log.clone())).await;
+    let broken = format!("http://{broken}");
+    let args = ["--proxy", &broken, "--proxy", "{proxy}", "--retry-duration", "0"];
+    let h = Harness::with_args(&args, &[]).await;
+    for _ in 0..2 {
+        assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    }
+    assert!(!log.all().is_empty());
+}
+
+#[tokio::test]
+async fn admin_rate_limited() {
+    let h =
+        Harness::new(&["--rate-limit", "1", "--rate-limit-burst", "1", "--admin-secret", "s3cret"])
+            .await;
+    assert_eq!(h.get("/admin/wrong").await.0, StatusCode::NOT_FOUND);
+    // guessing again is limited just like playlists
+    assert_eq!(h.get("/admin/guess").await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(h.get("/admin/s3cret").await.0, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[cfg(feature = "true-status")]
+#[tokio::test]
+async fn deep_status_skips_the_playlist_cache() {
+    use crate::sample::player_query;
+
+    let h = Harness::new(&[]).await;
+    let query: String =
+        url::form_urlencoded::Serializer::new(String::new()).extend_pairs(player_query()).finish();
+    assert_eq!(h.get(&format!("/live/abc?{query}")).await.0, StatusCode::OK);
+    // the featured stream is the one just served, asked for the same way, but it's fetched again
+    assert_eq!(h.get("/truestat/secret").await.0, StatusCode::OK);
+    assert_eq!(h.usher().len(), 2);
+}
+
+#[tokio::test]
+async fn vod_id_must_be_numeric() {
+    let h = Harness::new(&[]).await;
+    assert_eq!(h.get("/vod/abc").await.0, StatusCode::BAD_REQUEST);
+    assert!(h.proxy.all().is_empty());
+}
+
+#[tokio::test]
+async fn ttvlol_query_in_path() {
+    let h = Harness::new(&[]).await;
+    let path = "/playlist/AbC.m3u8%3Fallow_source%3Dtrue%26browser_family%3Dfirefox";
+    let (status, _, _) = h.get(path).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(h.gql()[0].json()["variables"]["login"], "abc");
+    let usher = &h.usher()[0];
+    assert_eq!(usher.uri.path(), "/api/channel/hls/abc.m3u8");
+    assert_eq!(usher.query("allow_source").as_deref(), Some("true"));
+    assert_eq!(usher.query("browser_family"), None);
+}
+
+#[tokio::test]
+async fn offline_channel() {
+    let h = Harness::new(&[]).await;
+    let (status, headers, body) = h.get("/live/offline").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(headers[header::CONTENT_TYPE], "application/json");
+    let error: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(error["code"], 404);
+    assert_eq!(error["kind"], "channel_offline");
+    // the token identifies the proxy's IP, and must not leak
+    let message = error["error"].as_str().unwrap();
+    assert!(!message.contains("sig=") && !message.contains("token="), "{message}");
+}
+
+#[tokio::test]
+async fn deleted_vod() {
+    let h = Harness::new(&[]).await;
+    let (status, _, body) = h.get("/vod/404").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let error: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(error["kind"], "vod_not_found");
+    assert!(h.usher().is_empty());
+}
+
+#[tokio::test]
+async fn geoblocked_channel() {
+    let h = Harness::new(&[]).await;
+    let (status, _, body) = h.get("/live/geoblocked").await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let error: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(error["kind"], "geo_blocked");
+    // not the token's fault, so it shouldn't have been fetched again
+    assert_eq!(h.gql().len(), 1);
+}
+
+#[tokio::test]
+async fn ads_retried_then_flagged() {
+    let h = Harness::with_args(&["--proxy", "{proxy}", "--proxy", "{proxy}"], &[]).await;
+    let (status, headers, body) = h.get("/live/preroll").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["x-luminous-ads"], "session_data");
+    assert!(body.starts_with("#EXTM3U"));
+    // each proxy got its own token, since a token is tied to the IP that fetched it
+    assert_eq!((h.gql().len(), h.usher().len()), (2, 2));
+    assert!(!h.get("/live/abc").await.1.contains_key("x-luminous-ads"));
+    assert_eq!(h.get("/live/stitched").await.1["x-luminous-ads"], "ad_break");
+}
+
+#[tokio::test]
+async fn ads_rejected() {
+    let h = Harness::new(&["--reject-ads"]).await;
+    let (status, _, body) = h.get("/live/preroll").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "ads_detected");
+    let (status, _, body) = h.get("/live/stitched").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert!(body.contains("twitch-stitched-ad"), "{body}");
+}
+
+#[tokio::test]
+async fn ad_country_fails_over() {
+    // checked against the built-in list by default
+    let h = Harness::new(&[]).await;
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    let (status, _, body) = h.get("/live/abroad").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "country_not_ad_free");
+    assert_eq!(h.get("/live/nowhere").await.0, StatusCode::BAD_GATEWAY);
+    let h = Harness::new(&["--ad-free-country", "none"]).await;
+    assert_eq!(h.get("/live/abroad").await.0, StatusCode::OK);
+    assert_eq!(h.get("/live/nowhere").await.0, StatusCode::OK);
+
+    let args = ["--proxy", "{proxy}", "--proxy", "{proxy}"];
+    let h = Harness::with_args(&args, &[]).await;
+    let (status, _, body) = h.get("/live/abroad").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "country_not_ad_free");
+    assert_eq!((h.gql().len(), h.usher().len()), (2, 2));
+
+    let h = Harness::new(&["--ad-free-country", "us,ca"]).await;
+    assert_eq!(h.get("/live/abroad").await.0, StatusCode::OK);
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::BAD_GATEWAY); // RU isn't on this list
+    // not saying where the proxy is doesn't get it past the check
+    let (status, _, body) = h.get("/live/nowhere").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "country_not_ad_free");
+}
+
+#[tokio::test]
+async fn hls_proxy_mode() {
+    let args = ["--hls-proxy", "--public-url", "https://ttv.example/", "--hls-allowed-host"];
+    let h = Harness::new(&[&args[..], &["127.0.0.1"]].concat()).await;
+    let (status, _, master) = h.get("/live/local").await;
+    assert_eq!(status, StatusCode::OK);
+    let variants: Vec<_> = master.lines().filter(|l| !l.starts_with('#')).collect();
+    assert_eq!(variants.len(), 2);
+    let variant = variants[0].strip_prefix("https://ttv.example").unwrap();
+    assert!(variant.starts_with("/hls/media.m3u8?u=http"), "{variant}");
+
+    let (status, _, media) = h.get(variant).await;
+    assert_eq!(status, StatusCode::OK);
+    // relative segments now point at where the playlist came from
+    let segment = media.lines().last().unwrap();
+    assert!(segment.starts_with("http://127.0.0.1:") && segment.ends_with("/v1/playlist/seg0.ts"));
+    assert_eq!(h.proxy.paths().last().unwrap(), "/v1/playlist/source.m3u8");
+    // it's a live stream, so the stitched ad is gone
+    assert!(!media.contains("ad0.ts") && !media.contains("stitched-ad"), "{media}");
+    assert!(media.contains("#EXT-X-MEDIA-SEQUENCE:10\n"));
+    assert_eq!(media.matches("#EXT-X-DISCONTINUITY").count(), 1);
+    #[cfg(feature = "metrics")]
+    assert!(
+        h.get("/metrics").await.2.contains("luminous_ad_segments_removed{channel=\"local\"} 1")
+    );
+    assert_eq!(
+        h.get("/hls/media.m3u8?u=http://example.com/x.m3u8").await.0,
+        StatusCode::BAD_REQUEST
+    );
+    // redirects aren't followed, since they could lead anywhere
+    let moved = variant.replace("source.m3u8", "moved.m3u8");
+    assert_eq!(h.get(&moved).await.0, StatusCode::BAD_GATEWAY);
+    assert_eq!(h.proxy.paths().last().unwrap(), "/v1/playlist/moved.m3u8");
+}
+
+#[tokio::test]
+async fn segment_relay() {
+    let args = ["--hls-proxy", "--public-url", "https://ttv.example/", "--relay-segments"];
+    let hosts = ["--hls-allowed-host", "127.0.0.1", "--relay-allowed-host", "127.0.0.1"];
+    let h = Harness::new(&[&args[..], &hosts[..]].concat()).await;
+    let (_, _, master) = h.get("/live/local").await;
+    let variant = master.lines().find(|l| !l.starts_with('#')).unwrap();
+    let (_, _, media) = h.get(variant.strip_prefix("https://ttv.example").unwrap()).await;
+    let segment = media.lines().last().unwrap();
+    assert!(segment.starts_with("https://ttv.example/hls/segment?u=http%3A%2F%2F127.0.0.1%3A"));
+
+    let segment = segment.strip_prefix("https://ttv.example").unwrap();
+    let (status, headers, body) = h.get_with(segment, &[(header::RANGE, "bytes=2-5")]).await;
+    assert_eq!(status, StatusCode::PARTIAL_CONTENT);
+    assert_eq!(headers[header::CONTENT_RANGE], "bytes 2-5/10");
+    assert_eq!(headers[header::CONTENT_TYPE], "video/mp2t");
+    assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+    assert_eq!(body, "2345");
+    // fetched directly, not through the proxy
+    assert_eq!(h.twitch.all().last().unwrap().header("range"), Some("bytes=2-5"));
+    assert!(!h.proxy.paths().iter().any(|p| p.ends_with(".ts")));
+    assert_eq!(
+        h.get("/hls/segment?u=https://example.com/seg0.ts").await.0,
+        StatusCode::BAD_REQUEST
+    );
+    let moved = segment.replace("seg0.ts", "moved.ts");
+    assert_eq!(h.get(&moved).await.0, StatusCode::BAD_GATEWAY);
+    assert!(h.twitch.paths().last().unwrap().ends_with("/moved.ts"));
+}
+
+#[tokio::test]
+async fn hls_proxy_off_by_default() {
+    let h = Harness::new(&[]).await;
+    let (_, _, master) = h.get("/live/abc").await;
+    assert!(master.contains("\nhttps://video-weaver.arn03.hls.ttvnw.net/"));
+    assert_eq!(
+        h.get("/hls/media.m3u8?u=https://a.ttvnw.net/x.m3u8").await.0,
+        StatusCode::NOT_FOUND
+    );
+}
+
+#[tokio::test]
+async fn user_agent_override() {
+    let h = Harness::new(&["--user-agent", "Override/2.0"]).await;
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    assert_eq!(h.gql()[0].header("user-agent"), Some("Override/2.0"));
+    assert_eq!(h.usher()[0].header("user-agent"), Some("Override/2.0"));
+}
+
+#[tokio::test]
+async fn status_endpoints() {
+    let h = Harness::new(&[]).await;
+    for path in ["/stat/", "/ping"] {
+        let (status, headers, body) = h.get(path).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        let status: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(status["online"], true);
+    }
+    assert!(h.proxy.all().is_empty());
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn metrics_endpoint() {
+    let h = Harness::new(&[]).await;
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    assert_eq!(h.get("/live/offline").await.0, StatusCode::NOT_FOUND);
+    let (status, headers, body) = h.get("/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(headers[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/plain"));
+    // other tests share the registry, so only check that series exist
+    for series in [
+        r#"luminous_requests_total{kind="live"}"#,
+        r#"luminous_upstream_responses_total{stage="m3u8",status="404"}"#,
+        r#"luminous_upstream_responses_total{stage="token",status="200"}"#,
+        r#"luminous_upstream_duration_seconds_count{stage="token"}"#,
+        r#"luminous_user_country_total{country="RU"}"#,
+        r#"luminous_token_cache{value="misses"}"#,
+    ] {
+        assert!(body.contains(series), "missing {series} in {body}");
+    }
+}
+
+#[cfg(feature = "hola")]
+#[tokio::test]
+async fn hola_bootstrap() {
+    use base64::Engine;
+
+    let h = Harness::with_args(&["--regen-creds", "--discard-creds", "--country", "RU"], &[]).await;
+    let hola = h.hola.all();
+    assert_eq!(h.hola.paths(), ["/background_init", "/zgettunnels"]);
+    let uuid = hola[0].query("uuid").unwrap();
+    assert_eq!(uuid.len(), 32);
+    let form: Vec<(String, String)> = serde_urlencoded::from_bytes(&hola[0].body).unwrap();
+    assert!(form.contains(&("login".to_owned(), "1".to_owned())));
+    assert_eq!(hola[1].query("country").as_deref(), Some("ru"));
+    assert_eq!(hola[1].query("session_key").as_deref(), Some("12345"));
+    assert_eq!(hola[1].query("uuid"), Some(uuid.clone()));
+
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    let credentials = format!("user-uuid-{uuid}:agentkey");
+    let expected = format!("Basic {}", base64::prelude::BASE64_STANDARD.encode(credentials));
+    let proxied = h.proxy.all();
+    assert_eq!(proxied.len(), 2);
+    for seen in proxied {
+        assert_eq!(seen.header("proxy-authorization"), Some(expected.as_str()));
+    }
+}
+
+#[tokio::test]
+async fn admin_swaps_proxy() {
+    let h = Harness::new(&["--admin-secret", "s3cret"]).await;
+    assert_eq!(h.get("/admin/wrong").await.0, StatusCode::NOT_FOUND);
+    let (status, _, body) = h.get("/admin/s3cret").await;
+    assert_eq!(status, StatusCode::OK);
+    let shown: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(shown["routes"][0]["source"], "custom");
+    assert_eq!(h.post("/admin/s3cret/proxy?url=nope").await.0, StatusCode::BAD_REQUEST);
+
+    // nothing listens there, so requests fail from now on
+    let (status, _, body) = h.post("/admin/s3cret/proxy?url=http://user:pw@127.0.0.1:9").await;
+    assert_eq!(status, StatusCode::OK);
+    let shown: Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(shown["routes"].as_array().unwrap().len(), 1);
+    assert!(shown["routes"][0]["label"].as_str().unwrap().starts_with("http://127.0.0.1:9#"));
+    assert_eq!(shown["routes"][0]["source"], "admin");
+    assert!(!body.contains("pw"));
+    let (status, _, body) = h.get("/live/abc").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "proxy_unreachable");
+    assert!(h.proxy.all().is_empty());
+}
+
+#[cfg(feature = "hola")]
+#[tokio::test]
+async fn admin_hola() {
+    let args = ["--regen-creds", "--discard-creds", "--country", "ru", "--admin-secret", "s3cret"];
+    let h = Harness::with_args(&args, &[]).await;
+    let (_, _, body) = h.get("/admin/s3cret").await;
+    let shown: Value = serde_json::from_str(&body).unwrap();
+    let uuid = shown["hola"]["uuid"].as_str().unwrap().to_owned();
+    assert_eq!(shown["hola"]["key"], "12***");
+    assert_eq!(shown["hola"]["countries"], json!(["ru"]));
+    assert_eq!(shown["routes"][0]["country"], "ru");
+
+    let (status, _, body) = h.post("/admin/s3cret/hola/tunnels?countries=UA,ru").await;
+    assert_eq!(status, StatusCode::OK);
+    let shown: Value = serde_json::from_str(&body).unwrap();
+    let countries: Vec<_> =
+        shown["routes"].as_array().unwrap().iter().map(|r| &r["country"]).collect();
+    assert_eq!(countries, ["ua", "ru"]);
+    assert_eq!(h.post("/admin/s3cret/hola/tunnels?countries=xyz").await.0, StatusCode::BAD_REQUEST);
+    // a refresh that fails leaves the old countries in place for the next one
+    assert!(!h.post("/admin/s3cret/hola/tunnels?countries=zz").await.0.is_success());
+    let (_, _, body) = h.get("/admin/s3cret").await;
+    assert_eq!(
+        serde_json::from_str::<Value>(&body).unwrap()["hola"]["countries"],
+        json!(["ua", "ru"])
+    );
+
+    let logins = || h.hola.paths().iter().filter(|p| *p == "/background_init").count();
+    let before = logins();
+    let (status, _, body) = h.post("/admin/s3cret/hola/login").await;
+    assert_eq!(status, StatusCode::OK);
+    let shown: Value = serde_json::from_str(&body).unwrap();
+    assert_ne!(shown["hola"]["uuid"], uuid.as_str());
+    assert_eq!(logins(), before + 1);
+    // still serving through the new tunnels, where Twitch places everything in RU
+    let (status, headers, _) = h.get("/live/abc").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["x-luminous-country"], "RU");
+
+    // switching to another proxy and back to Hola
+    h.post("/admin/s3cret/proxy?url=http://127.0.0.1:9").await;
+    let (_, _, body) = h.post("/admin/s3cret/hola/tunnels").await;
+    let shown: Value = serde_json::from_str(&body).unwrap();
+    assert!(shown["routes"].as_array().unwrap().iter().all(|r| r["source"] == "hola"), "{body}");
+}
+
+#[cfg(feature = "hola")]
+#[tokio::test]
+async fn hola_country_fallback() {
+    let args = ["--regen-creds", "--discard-creds", "--country", "ua,ru"];
+    let h = Harness::with_args(&args, &["--playlist-cache-ttl", "0"]).await;
+    let tunnels: Vec<_> = h.hola.all().iter().filter_map(|s| s.query("country")).collect();
+    assert_eq!(tunnels, ["ua", "ru"]);
+    // Twitch places every proxy in RU, so the UA one is skipped
+    let (status, headers, _) = h.get("/live/abc").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(headers["x-luminous-country"], "RU");
+    assert_eq!(h.usher().len(), 2);
+    // the UA route is out of rotation now, so RU is tried first
+    assert_eq!(h.get("/live/abc").await.0, StatusCode::OK);
+    assert_eq!(h.usher().len(), 3);
+    // a playlist that doesn't say where the proxy is can't show it's in the right country
+    let (status, _, body) = h.get("/live/nowhere").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "wrong_country");
+}
+
+#[cfg(feature = "hola")]
+#[tokio::test]
+async fn per_request_country() {
+    let args = ["--regen-creds", "--discard-creds", "--country", "ua", "--allowed-country", "ru"];
+    let h = Harness::with_args(&args, &[]).await;
+    let tunnels = || h.hola.all().iter().filter_map(|s| s.query("country")).collect::<Vec<_>>();
+    assert_eq!(tunnels(), ["ua"]);
+    // the pool has nothing in RU, so a tunnel is set up for it, once
+    for _ in 0..2 {
+        let (status, headers, _) = h.get("/c/RU/live/abc").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers["x-luminous-country"], "RU");
+    }
+    assert_eq!(tunnels(), ["ua", "ru"]);
+    assert_eq!(h.get("/vod/123?country=ru").await.0, StatusCode::OK);
+    assert_eq!(tunnels(), ["ua", "ru"]);
+    // not on the allowlist, even though the pool has it
+    for path in ["/c/ua/live/abc", "/live/abc?country=ua", "/c/tr/vod/123"] {
+        let (status, _, body) = h.get(path).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{path}");
+        assert_eq!(serde_json::from_str::<Value>(&body).unwrap()["kind"], "country_not_allowed");
+    }
+}
+
+#[cfg(feature = "hola")]
+#[tokio::test]
+async fn probe_countries() {
+    use crate::{Command, probe};
+
+    let args = ["--regen-creds", "--discard-creds"];
+    let h = Harness::with_args(&args, &["probe", "--channel", "preroll"]).await;
+    let Some(Command::Probe(probe_args)) = &h.opts.command else { unreachable!() };
+    let report = serde_json::to_value(probe::probe(&h.opts, probe_args).await.unwrap()).unwrap();
+    // countries weren't given, so all of Hola's are probed
+    let countries = report["countries"].as_array().unwrap();
+    let probed: Vec<_> = countries.iter().map(|o| o["country"].as_str().unwrap()).collect();
+    assert_eq!(probed, ["ru", "ua"]);
+    for outcome in countries {
+        assert_eq!(outcome["user_country"], "RU");
+        assert!(outcome["ads"].as_str().unwrap().contains("preroll"), "{outcome}");
+        assert!(outcome["token_ms"].is_u64() && outcome["playlist_ms"].is_u64(), "{outcome}");
+    }
+    assert_eq!(report["ad_free"], json!([]));
+
+    let h = Harness::with_args(&args, &["probe", "--countries", "UA", "--channel", "abc"]).await;
+    let Some(Command::Probe(probe_args)) = &h.opts.command else { unreachable!() };
+    let report = serde_json::to_value(probe::probe(&h.opts, probe_args).await.unwrap()).unwrap();
+    assert_eq!(report["countries"][0]["error"], Value::Null);
+    assert_eq!(report["ad_free"], json!(["ua"]));
+    let tunnels: Vec<_> = h.hola.all().iter().filter_map(|s| s.query("country")).collect();
+    assert!(tunnels.ends_with(&["ua".to_owned()]), "{tunnels:?}");
+}
+
+#[tokio::test]
+async fn fetch_once() {
+    use crate::{Command, fetch};
+
+    let output = std::env::temp_dir().join(format!("luminous-ttv-fetch-{}", std::process::id()));
+    let run = |args: &'static [&'static str]| {
+        let output = output.to_str().unwrap().to_owned();
+        async move {
+            let extra: Vec<&str> =
+                ["fetch", "--output", &output].iter().chain(args).copied().collect();
+            let h = Harness::new(&extra).await;
+            let Some(Command::Fetch(fetch_args)) = &h.opts.command else { unreachable!() };
+            (fetch::run(&h.opts, fetch_args).await, h)
+        }
+    };
+    let (code, h) = run(&["live", "abc"]).await;
+    assert_eq!(code, 0);
+    assert!(std::fs::read_to_string(&output).unwrap().starts_with("#EXTM3U"));
+    assert_eq!(h.proxy.all().len(), 2); // token and playlist went through the proxy
+    let (code, _) = run(&["--best", "vod", "123"]).await;
+    assert_eq!(code, 0);
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "https://video-weaver.arn03.hls.ttvnw.net/v1/playlist/source.m3u8\n"
+    );
+    std::fs::remove_file(&output).unwrap();
+    assert_eq!(run(&["live", "offline"]).await.0, fetch::EXIT_OFFLINE);
+    assert_eq!(run(&["vod", "404"]).await.0, fetch::EXIT_NOT_FOUND);
+    assert_eq!(run(&["vod", "abc"]).await.0, 1);
+    assert!(!output.exists());
+    // nothing listens on the discard port
+    let args = ["--proxy", "http://127.0.0.1:9", "--retry-duration", "0"];
+    let h = Harness::with_args(&args, &["fetch", "live", "abc"]).await;
+    let Some(Command::Fetch(fetch_args)) = &h.opts.command else { unreachable!() };
+    assert_eq!(fetch::run(&h.opts, fetch_args).await, fetch::EXIT_PROXY_FAILED);
+}
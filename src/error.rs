@@ -0,0 +1,246 @@
+//! Errors returned to clients. Each has a stable `kind` string so the extension can decide
+//! whether to fall back to the normal (ad-filled) stream or just show an error.
+
+use axum::{Json, body::Body, response::IntoResponse};
+use http::{Response, StatusCode, header::RETRY_AFTER};
+use serde::Deserialize;
+use serde_json::json;
+
+pub(crate) type AppResult<T> = std::result::Result<T, AppError>;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum AppError {
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("channel is offline")]
+    ChannelOffline,
+    #[error("channel does not exist")]
+    ChannelNotFound,
+    #[error("VOD does not exist or was deleted")]
+    VodNotFound,
+    #[error("clip does not exist or was deleted")]
+    ClipNotFound,
+    /// The client asked for a country the operator doesn't allow.
+    #[error("country {0} is not available on this server")]
+    CountryNotAllowed(String),
+    /// Twitch won't serve this content in the country it thinks the proxy is in.
+    #[error("content is restricted in the proxy's region ({0})")]
+    GeoBlocked(String),
+    /// Usher refused the access token.
+    #[error("access token was rejected")]
+    TokenRejected,
+    #[error("GQL returned errors: {}", .0.join("; "))]
+    Gql(Vec<String>),
+    /// Couldn't get a response at all; most likely the proxy's fault.
+    #[error("could not reach upstream: {0}")]
+    ProxyUnreachable(String),
+    /// Twitch placed the proxy somewhere other than the country it was requested in.
+    #[error("proxy exits in {actual}, not {expected}")]
+    WrongCountry { expected: String, actual: String },
+    /// Twitch placed the proxy in a country where it serves ads.
+    #[error("proxy is in {0}, which isn't on the ad-free list")]
+    AdCountry(String),
+    /// Every route got a playlist that looks like it has ads.
+    #[error("every proxy seems to get ads: {0}")]
+    AdsDetected(String),
+    #[error("{stage} request failed with HTTP {status}")]
+    Upstream { stage: &'static str, status: StatusCode },
+    #[error("service is overloaded, try again later")]
+    Overloaded,
+    #[error("too many requests, try again in {retry_after} seconds")]
+    RateLimited { retry_after: u64 },
+    #[error("timeout")]
+    Timeout,
+    #[error(transparent)]
+    Internal(#[from] anyhow::Error),
+}
+
+impl AppError {
+    /// Stable identifier for clients to match on. Don't change these.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::ChannelOffline => "channel_offline",
+            Self::ChannelNotFound => "channel_not_found",
+            Self::VodNotFound => "vod_not_found",
+            Self::ClipNotFound => "clip_not_found",
+            Self::CountryNotAllowed(_) => "country_not_allowed",
+            Self::GeoBlocked(_) => "geo_blocked",
+            Self::TokenRejected => "token_rejected",
+            Self::Gql(_) => "gql_error",
+            Self::ProxyUnreachable(_) => "proxy_unreachable",
+            Self::WrongCountry { .. } => "wrong_country",
+            Self::AdCountry(_) => "country_not_ad_free",
+            Self::AdsDetected(_) => "ads_detected",
+            Self::Upstream { .. } => "upstream_error",
+            Self::Overloaded => "overloaded",
+            Self::RateLimited { .. } => "rate_limited",
+            Self::Timeout => "timeout",
+            Self::Internal(_) => "internal",
+        }
+    }
+
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::ChannelOffline
+            | Self::ChannelNotFound
+            | Self::VodNotFound
+            | Self::ClipNotFound => StatusCode::NOT_FOUND,
+            Self::GeoBlocked(_) | Self::CountryNotAllowed(_) => StatusCode::FORBIDDEN,
+            Self::TokenRejected
+            | Self::Gql(_)
+            | Self::ProxyUnreachable(_)
+            | Self::WrongCountry { .. }
+            | Self::AdCountry(_)
+            | Self::AdsDetected(_)
+            | Self::Upstream { .. } => StatusCode::BAD_GATEWAY,
+            Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// A copy for another request that was waiting on the same upstream fetch. Internal errors
+    /// only keep their message.
+    pub(crate) fn duplicate(&self) -> Self {
+        match self {
+            Self::BadRequest(s) => Self::BadRequest(s.clone()),
+            Self::ChannelOffline => Self::ChannelOffline,
+            Self::ChannelNotFound => Self::ChannelNotFound,
+            Self::VodNotFound => Self::VodNotFound,
+            Self::ClipNotFound => Self::ClipNotFound,
+            Self::CountryNotAllowed(s) => Self::CountryNotAllowed(s.clone()),
+            Self::GeoBlocked(s) => Self::GeoBlocked(s.clone()),
+            Self::TokenRejected => Self::TokenRejected,
+            Self::Gql(errors) => Self::Gql(errors.clone()),
+            Self::ProxyUnreachable(s) => Self::ProxyUnreachable(s.clone()),
+            Self::WrongCountry { expected, actual } => {
+                Self::WrongCountry { expected: expected.clone(), actual: actual.clone() }
+            }
+            Self::AdCountry(s) => Self::AdCountry(s.clone()),
+            Self::AdsDetected(s) => Self::AdsDetected(s.clone()),
+            Self::Upstream { stage, status } => Self::Upstream { stage, status: *status },
+            Self::Overloaded => Self::Overloaded,
+            Self::RateLimited { retry_after } => Self::RateLimited { retry_after: *retry_after },
+            Self::Timeout => Self::Timeout,
+            Self::Internal(e) => Self::Internal(anyhow::anyhow!("{e:#}")),
+        }
+    }
+
+    /// Whether the route (proxy) is to blame, so the request is worth retrying on another one.
+    /// That includes errors a plain HTTP proxy answers with itself: it wants credentials, or it
+    /// couldn't reach Twitch's API, which is otherwise always up.
+    pub(crate) fn is_route_failure(&self) -> bool {
+        match self {
+            Self::ProxyUnreachable(_) | Self::WrongCountry { .. } | Self::AdCountry(_) => true,
+            Self::Upstream { status: StatusCode::PROXY_AUTHENTICATION_REQUIRED, .. } => true,
+            Self::Upstream { stage: "token", status } => matches!(
+                *status,
+                StatusCode::BAD_GATEWAY
+                    | StatusCode::SERVICE_UNAVAILABLE
+                    | StatusCode::GATEWAY_TIMEOUT
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<reqwest::Error> for AppError {
+    fn from(e: reqwest::Error) -> Self {
+        // vaporize the URL since its query string has the token, which has the IP Twitch sees
+        let e = e.without_url();
+        if e.is_connect() || e.is_timeout() || e.is_request() || e.is_body() {
+            Self::ProxyUnreachable(e.to_string())
+        } else {
+            Self::Internal(e.into())
+        }
+    }
+}
+
+impl From<reqwest_middleware::Error> for AppError {
+    fn from(e: reqwest_middleware::Error) -> Self {
+        match e {
+            reqwest_middleware::Error::Reqwest(e) => e.into(),
+            // only the retry middleware is in use, so this means it gave up
+            reqwest_middleware::Error::Middleware(_) => {
+                Self::ProxyUnreachable("request failed after retries".to_owned())
+            }
+        }
+    }
+}
+
+/// Usher's error bodies look like `[{"error": "...", "error_code": "...", ...}]`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct UsherError {
+    #[serde(default)]
+    pub(crate) error: String,
+    #[serde(default)]
+    pub(crate) error_code: String,
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response<Body> {
+        let status = self.status();
+        crate::access_log::error(self.kind());
+        let body = Json(json!({
+            "code": status.as_u16(),
+            "kind": self.kind(),
+            "error": format!("{self:#}"),
+        }));
+        let mut response = (status, body).into_response();
+        if let Self::RateLimited { retry_after } = self {
+            response.headers_mut().insert(RETRY_AFTER, retry_after.into());
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::StatusCode;
+
+    use crate::error::AppError;
+
+    #[test]
+    fn kinds_and_statuses() {
+        let cases = [
+            (AppError::ChannelOffline, "channel_offline", StatusCode::NOT_FOUND),
+            (AppError::VodNotFound, "vod_not_found", StatusCode::NOT_FOUND),
+            (AppError::ClipNotFound, "clip_not_found", StatusCode::NOT_FOUND),
+            (AppError::GeoBlocked("x".into()), "geo_blocked", StatusCode::FORBIDDEN),
+            (AppError::Gql(vec!["bad".into()]), "gql_error", StatusCode::BAD_GATEWAY),
+            (AppError::ProxyUnreachable("x".into()), "proxy_unreachable", StatusCode::BAD_GATEWAY),
+            (
+                AppError::RateLimited { retry_after: 1 },
+                "rate_limited",
+                StatusCode::TOO_MANY_REQUESTS,
+            ),
+            (
+                AppError::Internal(anyhow::anyhow!("x")),
+                "internal",
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ),
+        ];
+        for (error, kind, status) in cases {
+            assert_eq!(error.kind(), kind);
+            assert_eq!(error.status(), status);
+        }
+        assert!(AppError::ProxyUnreachable("x".into()).is_route_failure());
+        assert!(!AppError::ChannelOffline.is_route_failure());
+        let upstream = |stage, status| AppError::Upstream { stage, status };
+        assert!(upstream("token", StatusCode::BAD_GATEWAY).is_route_failure());
+        assert!(upstream("m3u8", StatusCode::PROXY_AUTHENTICATION_REQUIRED).is_route_failure());
+        assert!(!upstream("m3u8", StatusCode::BAD_GATEWAY).is_route_failure()); // usher's own
+        assert!(!upstream("token", StatusCode::BAD_REQUEST).is_route_failure());
+        let wrong = AppError::WrongCountry { expected: "RU".into(), actual: "US".into() };
+        assert_eq!(wrong.kind(), "wrong_country");
+        assert!(wrong.is_route_failure());
+        let ads = AppError::AdCountry("US".into());
+        assert_eq!((ads.kind(), ads.status()), ("country_not_ad_free", StatusCode::BAD_GATEWAY));
+        assert!(ads.is_route_failure());
+        let copy = AppError::Internal(anyhow::anyhow!("inner").context("outer")).duplicate();
+        assert_eq!((copy.kind(), copy.to_string()), ("internal", "outer: inner".to_owned()));
+    }
+}
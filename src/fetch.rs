@@ -0,0 +1,141 @@
+//! The `fetch` subcommand: gets one master playlist the way the server would, prints it or its
+//! best variant, and exits. It's for handing streams to mpv, streamlink or yt-dlp from scripts
+//! without running the server. The exit code says why there's no playlist, if there isn't one.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::error::{AppError, AppResult};
+use crate::m3u8::Playlist;
+use crate::{LState, Opts, ProcessData, StreamID, common, parse_country, sample, start_proxies};
+
+/// Exit codes for the failures scripts are likely to handle. Anything else exits with 1.
+pub(crate) const EXIT_OFFLINE: i32 = 3;
+pub(crate) const EXIT_NOT_FOUND: i32 = 4;
+pub(crate) const EXIT_PROXY_FAILED: i32 = 5;
+
+#[derive(Args, Clone, Debug, PartialEq)]
+pub(crate) struct FetchArgs {
+    #[arg(value_enum)]
+    kind: Kind,
+    /// Channel name, or VOD ID.
+    id: String,
+    /// Print the URL of the variant with the highest bandwidth instead of the whole playlist,
+    /// after `--max-resolution` and the like.
+    #[arg(long)]
+    best: bool,
+    /// Country to fetch through, if it's allowed with `--allowed-country`.
+    #[arg(long, value_parser = parse_country)]
+    country: Option<String>,
+    /// Write to this file instead of stdout.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Kind {
+    Live,
+    Vod,
+}
+
+/// Fetch, print, and return the exit code.
+pub(crate) async fn run(opts: &Opts, args: &FetchArgs) -> i32 {
+    let state = match start_proxies(opts).await.and_then(|proxies| LState::new(opts, proxies)) {
+        Ok(state) => state,
+        Err(e) => {
+            error!("failed to set up proxies: {e:#}");
+            return EXIT_PROXY_FAILED;
+        }
+    };
+    let text = match fetch(&state, args).await {
+        Ok(text) => text,
+        Err(e) => {
+            error!("{e}");
+            return exit_code(&e);
+        }
+    };
+    if let Err(e) = write(args, &text) {
+        error!("{e:#}");
+        return 1;
+    }
+    0
+}
+
+pub(crate) async fn fetch(state: &LState, args: &FetchArgs) -> AppResult<String> {
+    let sid = match args.kind {
+        Kind::Live => StreamID::Live(args.id.to_ascii_lowercase()),
+        Kind::Vod if args.id.parse::<u64>().is_err() => {
+            return Err(AppError::BadRequest("VOD ID must be numeric".to_owned()));
+        }
+        Kind::Vod => StreamID::VOD(args.id.clone()),
+    };
+    if let Some(country) = &args.country
+        && !state.allows_country(country)
+    {
+        return Err(AppError::CountryNotAllowed(country.clone()));
+    }
+    let pd = ProcessData {
+        sid,
+        query: sample::player_query(),
+        user_agent: common::get_user_agent(None, state.user_agent.as_ref())?,
+        hls_endpoint: None,
+        country: args.country.clone(),
+        variants: state.variants.clone(),
+    };
+    let response = crate::process(pd, state).await?;
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+        .await
+        .map_err(|e| AppError::Internal(e.into()))?;
+    let m3u8 = String::from_utf8_lossy(&body).into_owned();
+    if !args.best {
+        return Ok(m3u8);
+    }
+    let playlist = Playlist::parse(&m3u8);
+    crate::variants::best(&playlist)
+        .map(|uri| format!("{uri}\n"))
+        .ok_or_else(|| AppError::Internal(anyhow::anyhow!("the playlist has no variants")))
+}
+
+fn write(args: &FetchArgs, text: &str) -> Result<()> {
+    match &args.output {
+        Some(path) => fs::write(path, text).with_context(|| format!("writing {}", path.display())),
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(text.as_bytes())?;
+            Ok(stdout.flush()?)
+        }
+    }
+}
+
+/// What a failure means for scripts: the stream isn't live, doesn't exist, or couldn't be reached
+/// through any proxy.
+pub(crate) fn exit_code(e: &AppError) -> i32 {
+    match e {
+        AppError::ChannelOffline => EXIT_OFFLINE,
+        AppError::ChannelNotFound | AppError::VodNotFound => EXIT_NOT_FOUND,
+        AppError::GeoBlocked(_) | AppError::AdsDetected(_) => EXIT_PROXY_FAILED,
+        e if e.is_route_failure() => EXIT_PROXY_FAILED,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::AppError;
+    use crate::fetch::{EXIT_NOT_FOUND, EXIT_OFFLINE, EXIT_PROXY_FAILED, exit_code};
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(&AppError::ChannelOffline), EXIT_OFFLINE);
+        assert_eq!(exit_code(&AppError::VodNotFound), EXIT_NOT_FOUND);
+        assert_eq!(exit_code(&AppError::ProxyUnreachable("x".into())), EXIT_PROXY_FAILED);
+        assert_eq!(exit_code(&AppError::AdCountry("US".into())), EXIT_PROXY_FAILED);
+        assert_eq!(exit_code(&AppError::BadRequest("x".into())), 1);
+    }
+}
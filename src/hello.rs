@@ -29,7 +29,6 @@ use std::str::FromStr;
 use std::time::Duration;
 
 use anyhow::{Result, anyhow};
-use const_format::concatcp;
 use isocountry::CountryCode;
 use once_cell::sync::Lazy;
 use rand::{RngExt, rng};
@@ -49,9 +48,8 @@ use crate::common;
 const EXT_VER: &str = "1.186.727";
 const EXT_BROWSER: (&str, &str) = ("browser", "firefox"); // or chrome
 const PRODUCT: (&str, &str) = ("product", "www"); // "cws" for Chrome Web Store
-const CCGI_URL: &str = "https://client.hola.org/client_cgi/";
-const BG_INIT_URL: &str = concatcp!(CCGI_URL, "background_init");
-const ZGETTUNNELS_URL: &str = concatcp!(CCGI_URL, "zgettunnels");
+/// Default base for the Hola API, overridable with `--hola-url`.
+pub(crate) const CCGI_URL: &str = "https://client.hola.org/client_cgi/";
 
 #[allow(dead_code)] // silence clippy; this is logged
 #[derive(Clone, Debug, PartialEq, Deserialize)]
@@ -77,19 +75,22 @@ static CLIENT: Lazy<Client> = Lazy::new(|| {
         .unwrap()
 });
 
-const VPN_COUNTRIES_URL: &str = concatcp!(CCGI_URL, "vpn_countries.json");
-
-pub(crate) async fn list_countries() -> Result<()> {
-    // This prints to console, which isn't really proper API design, but whatever
+/// Codes of the countries Hola has proxies in, lowercase.
+pub(crate) async fn countries(ccgi: &Url) -> Result<Vec<String>> {
     let countries: Vec<String> = CLIENT
-        .get(VPN_COUNTRIES_URL)
+        .get(ccgi.join("vpn_countries.json")?)
         .header(EXT_BROWSER.0, EXT_BROWSER.1)
         .send()
         .await?
         .error_for_status()?
         .json()
         .await?;
-    for code in countries {
+    Ok(countries.into_iter().map(|c| c.to_ascii_lowercase()).collect())
+}
+
+pub(crate) async fn list_countries(ccgi: &Url) -> Result<()> {
+    // This prints to console, which isn't really proper API design, but whatever
+    for code in countries(ccgi).await? {
         // Hola incorrectly specifies the UK as "UK", but its alpha-2 code is GB
         // fixup the display without changing the code, since we pass that to the API
         let country = if code.eq_ignore_ascii_case("uk") {
@@ -167,13 +168,14 @@ pub(crate) struct TunnelResponse {
 }
 
 pub(crate) async fn get_tunnels(
+    ccgi: &Url,
     uuid: &Uuid,
     session_key: i64,
     country: &str,
     proxy_type: ProxyType,
     limit: u32,
 ) -> Result<TunnelResponse> {
-    let mut url = Url::parse(ZGETTUNNELS_URL).expect("zgettunnels");
+    let mut url = ccgi.join("zgettunnels")?;
     url.query_pairs_mut()
         .append_pair("country", &proxy_type.to_param(country))
         .append_pair("limit", &limit.to_string())
@@ -188,10 +190,13 @@ pub(crate) async fn get_tunnels(
 }
 
 /// Login to Hola. Generates a random UUID unless one is provided.
-pub(crate) async fn background_init(uuid: Option<Uuid>) -> Result<(BgInitResponse, Uuid)> {
+pub(crate) async fn background_init(
+    ccgi: &Url,
+    uuid: Option<Uuid>,
+) -> Result<(BgInitResponse, Uuid)> {
     debug!("bg_init using UUID {:?}", uuid);
     let uuid = uuid.unwrap_or_else(Uuid::new_v4);
-    let mut url = Url::parse(BG_INIT_URL)?;
+    let mut url = ccgi.join("background_init")?;
     url.query_pairs_mut().append_pair("uuid", &uuid.as_simple().to_string());
     let login = &[("login", "1"), ("ver", EXT_VER)];
     let resp =
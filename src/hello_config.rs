@@ -1,62 +1,548 @@
 //! Stores some of the Hola code to make conditional compilation cleaner. I should probably
 //! move more code into this file.
 
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use anyhow::{Context, Result, bail};
 use rand::{prelude::IndexedRandom, rng};
 use reqwest::Proxy;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, info};
+use tokio::sync::{Mutex, OnceCell};
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+use url::Url;
 use uuid::Uuid;
 
-use hello::ProxyType;
+use hello::{ProxyType, TunnelResponse};
 
-use crate::{Opts, hello, hello::BgInitResponse};
+use crate::{
+    ClientSettings, Opts, hello,
+    hello::BgInitResponse,
+    pool::{ProxyPool, Route, RouteSpec, Source},
+};
 
 const CRATE_NAME: &str = env!("CARGO_PKG_NAME");
+/// First delay after a failed refresh; doubles on each further failure.
+const MIN_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
 
+/// What's kept between runs, in the profile's confy file.
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub(crate) struct Config {
     uuid: Option<Uuid>,
+    /// From the last login with `uuid`.
+    #[serde(default)]
+    session: Option<SavedSession>,
+    /// The last tunnels fetched for each country with that session.
+    #[serde(default)]
+    tunnels: HashMap<String, SavedTunnels>,
+}
+
+#[derive(Copy, Clone, Debug, Deserialize, Serialize)]
+struct SavedSession {
+    key: i64,
+    /// Unix timestamp, in seconds.
+    logged_in: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct SavedTunnels {
+    /// Unix timestamp, in seconds.
+    fetched: u64,
+    response: TunnelResponse,
+}
+
+impl Config {
+    /// The saved session, if it's younger than `ttl`.
+    fn session(&self, ttl: Duration, now: SystemTime) -> Option<Session> {
+        let saved = self.session?;
+        (age(saved.logged_in, now) < ttl).then_some(Session { uuid: self.uuid?, key: saved.key })
+    }
+
+    /// Tunnels saved for `country`, if they're younger than `ttl`. Forever if `None`.
+    fn tunnels(
+        &self,
+        country: &str,
+        ttl: Option<Duration>,
+        now: SystemTime,
+    ) -> Option<&TunnelResponse> {
+        let saved = self.tunnels.get(country)?;
+        ttl.is_none_or(|ttl| age(saved.fetched, now) < ttl).then_some(&saved.response)
+    }
+}
+
+fn timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn age(timestamp: u64, now: SystemTime) -> Duration {
+    now.duration_since(UNIX_EPOCH + Duration::from_secs(timestamp)).unwrap_or_default()
 }
 
-/// Connect to Hola, retrieve tunnels, return a Proxy. Updates
-/// stored UUID in the config if we regenerated our creds.
-pub(crate) async fn setup_hola(opts: &Opts) -> Result<Proxy> {
-    info!(
-        "Setting up Hola proxy. Regen: {} / Discard: {} / Country: {}",
-        opts.regen_creds, opts.discard_creds, opts.country
-    );
-    let mut config: Config = confy::load(CRATE_NAME, None)?;
-    let uuid = if !opts.regen_creds { config.uuid } else { None };
-    let (bg, uuid) = hello::background_init(uuid).await.context("Hola init")?;
-    config.uuid = Some(uuid);
-    let key = match bg {
-        BgInitResponse::Success { key, .. } => key,
-        BgInitResponse::Block { .. } => {
-            bail!("You've been blocked by Hola. Try re-running with --regen-creds. ({bg:?})");
+/// Returned when `background_init` says we're blocked.
+#[derive(Debug)]
+struct Blocked(BgInitResponse);
+
+impl fmt::Display for Blocked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "blocked by Hola ({:?})", self.0)
+    }
+}
+
+impl std::error::Error for Blocked {}
+
+#[derive(Copy, Clone, Debug)]
+struct Session {
+    uuid: Uuid,
+    key: i64,
+}
+
+/// A logged-in Hola session, which can hand out tunnels and keep the pool's Hola routes fresh.
+#[derive(Debug)]
+pub(crate) struct Hola {
+    ccgi: Url,
+    /// In order of preference.
+    countries: RwLock<Vec<String>>,
+    discard_creds: bool,
+    /// Which confy file the session is saved in.
+    profile: Option<String>,
+    /// How long saved tunnels are good for. Forever if `None`.
+    tunnel_ttl: Option<Duration>,
+    session: Mutex<Session>,
+    saved: std::sync::Mutex<Config>,
+    /// Set while an operator has swapped the pool over to some other proxy, so refreshes don't
+    /// put Hola back.
+    paused: AtomicBool,
+}
+
+impl Hola {
+    /// Connect to Hola, reusing the saved session if it's recent enough. Otherwise logs in,
+    /// saving the new session unless told not to.
+    pub(crate) async fn setup(opts: &Opts) -> Result<Self> {
+        info!(
+            "Setting up Hola proxy. Regen: {} / Discard: {} / Countries: {} / Profile: {}",
+            opts.regen_creds,
+            opts.discard_creds,
+            opts.country.join(","),
+            opts.profile.as_deref().unwrap_or("default")
+        );
+        let saved = if !opts.regen_creds {
+            confy::load(CRATE_NAME, opts.profile.as_deref())?
+        } else {
+            Config::default()
+        };
+        let reused = saved.session(Duration::from_secs(opts.hola_session_ttl), SystemTime::now());
+        let session = match reused {
+            Some(session) => {
+                info!("reusing saved Hola session");
+                session
+            }
+            None => login(&opts.hola_url, saved.uuid).await.map_err(|e| {
+                if e.is::<Blocked>() {
+                    e.context("You've been blocked by Hola. Try re-running with --regen-creds.")
+                } else {
+                    e
+                }
+            })?,
+        };
+        let hola = Self {
+            ccgi: opts.hola_url.clone(),
+            countries: RwLock::new(opts.country.clone()),
+            discard_creds: opts.discard_creds,
+            profile: opts.profile.clone(),
+            tunnel_ttl: (opts.hola_refresh_interval > 0)
+                .then(|| Duration::from_secs(opts.hola_refresh_interval)),
+            session: Mutex::new(session),
+            saved: std::sync::Mutex::new(saved),
+            paused: AtomicBool::new(false),
+        };
+        if reused.is_none() {
+            hola.remember(session)?;
         }
-    };
-    let proxy_type = ProxyType::Direct;
-    let tunnels = hello::get_tunnels(&uuid, key, &opts.country, proxy_type, 3).await?;
+        Ok(hola)
+    }
+
+    /// Retrieve tunnels and return a route through one of them for each country, in order of
+    /// preference. Countries without tunnels are skipped, unless that's all of them. Saved
+    /// tunnels are used where they haven't expired, and if they don't work out, we log in again.
+    pub(crate) async fn tunnels(&self) -> Result<Vec<RouteSpec>> {
+        let mut session = self.session.lock().await;
+        self.tunnels_or_login(&mut session, true).await
+    }
+
+    async fn tunnels_with(&self, session: &Session, reuse: bool) -> Result<Vec<RouteSpec>> {
+        let mut routes = vec![];
+        let mut last_error = None;
+        let mut fetched = HashMap::new();
+        let countries = self.countries.read().unwrap().clone();
+        for (priority, country) in countries.iter().enumerate() {
+            let saved = reuse
+                .then(|| {
+                    let saved = self.saved.lock().unwrap();
+                    saved.tunnels(country, self.tunnel_ttl, SystemTime::now()).cloned()
+                })
+                .flatten();
+            let response = match saved {
+                Some(response) => {
+                    debug!("reusing saved Hola tunnels for {country}");
+                    Ok(response)
+                }
+                None => fetch_tunnels(&self.ccgi, session, country).await.inspect(|response| {
+                    fetched.insert(country.clone(), response.clone());
+                }),
+            };
+            match response.and_then(|response| route_through(session, country, &response)) {
+                Ok(route) => routes.push(RouteSpec { priority, ..route }),
+                Err(e) => {
+                    warn!("no Hola tunnel for {country}: {e:#}");
+                    last_error = Some(e);
+                }
+            }
+        }
+        if !fetched.is_empty() {
+            let mut saved = self.saved.lock().unwrap();
+            let now = timestamp(SystemTime::now());
+            saved.tunnels.extend(
+                fetched
+                    .into_iter()
+                    .map(|(c, response)| (c, SavedTunnels { fetched: now, response })),
+            );
+            self.save(&saved)?;
+        }
+        match last_error {
+            Some(e) if routes.is_empty() => Err(e),
+            _ => Ok(routes),
+        }
+    }
+
+    /// Fetch tunnels, logging in again if our session key has stopped working, and
+    /// regenerating credentials if Hola has blocked us.
+    async fn tunnels_or_login(&self, session: &mut Session, reuse: bool) -> Result<Vec<RouteSpec>> {
+        match self.tunnels_with(session, reuse).await {
+            Ok(routes) => Ok(routes),
+            Err(e) => {
+                warn!("fetching Hola tunnels failed, logging in again: {e:#}");
+                *session = match self.login(Some(session.uuid)).await {
+                    Err(e) if e.is::<Blocked>() => {
+                        warn!("{e}, regenerating credentials");
+                        self.login(None).await?
+                    }
+                    other => other?,
+                };
+                self.tunnels_with(session, false).await
+            }
+        }
+    }
+
+    /// Login to Hola, generating a new UUID unless one is provided.
+    async fn login(&self, uuid: Option<Uuid>) -> Result<Session> {
+        let session = login(&self.ccgi, uuid).await?;
+        self.remember(session)?;
+        Ok(session)
+    }
+
+    /// Save a new session, unless told not to. Saved tunnels are forgotten, since they belong to
+    /// the old one.
+    fn remember(&self, session: Session) -> Result<()> {
+        let mut saved = self.saved.lock().unwrap();
+        *saved = Config {
+            uuid: Some(session.uuid),
+            session: Some(SavedSession {
+                key: session.key,
+                logged_in: timestamp(SystemTime::now()),
+            }),
+            tunnels: HashMap::new(),
+        };
+        self.save(&saved)
+    }
+
+    fn save(&self, config: &Config) -> Result<()> {
+        if self.discard_creds {
+            return Ok(());
+        }
+        let profile = self.profile.as_deref();
+        debug!(
+            "Saving Hola session to {}",
+            confy::get_configuration_file_path(CRATE_NAME, profile)?.display()
+        );
+        Ok(confy::store(CRATE_NAME, profile, config)?)
+    }
+
+    /// How long ago the oldest of the current countries' saved tunnels were fetched.
+    fn tunnels_age(&self) -> Duration {
+        let saved = self.saved.lock().unwrap();
+        let now = SystemTime::now();
+        self.countries
+            .read()
+            .unwrap()
+            .iter()
+            .filter_map(|country| saved.tunnels.get(country))
+            .map(|tunnels| age(tunnels.fetched, now))
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// A route through a new tunnel in `country`, which isn't saved or added to the pool.
+    pub(crate) async fn route_in(&self, country: &str) -> Result<RouteSpec> {
+        let session = *self.session.lock().await;
+        let tunnels = fetch_tunnels(&self.ccgi, &session, country).await?;
+        route_through(&session, country, &tunnels)
+    }
+
+    /// The session's UUID and key.
+    pub(crate) async fn credentials(&self) -> (Uuid, i64) {
+        let session = *self.session.lock().await;
+        (session.uuid, session.key)
+    }
+
+    pub(crate) fn countries(&self) -> Vec<String> {
+        self.countries.read().unwrap().clone()
+    }
+
+    /// Change the countries tunnels are fetched for, from the next refresh on.
+    pub(crate) fn set_countries(&self, countries: Vec<String>) {
+        *self.countries.write().unwrap() = countries;
+    }
+
+    /// Stop refreshes from touching the pool, because it's been given other proxies.
+    pub(crate) fn pause(&self) {
+        self.paused.store(true, Ordering::Release);
+    }
+
+    /// Log in again with new credentials, then swap in tunnels fetched with them.
+    pub(crate) async fn relogin(&self, pool: &ProxyPool) -> Result<()> {
+        let mut session = self.session.lock().await;
+        *session = self.login(None).await?;
+        info!("logged in to Hola again with new credentials");
+        let routes = self.tunnels_with(&session, false).await?;
+        self.swap_in(pool, routes)
+    }
+
+    /// Fetch new tunnels and swap them into the pool, logging in again if need be.
+    pub(crate) async fn refresh(&self, pool: &ProxyPool) -> Result<()> {
+        let mut session = self.session.lock().await;
+        let routes = self.tunnels_or_login(&mut session, false).await?;
+        self.swap_in(pool, routes)
+    }
+
+    /// Replace the pool's Hola routes, or everything if the pool had been handed over to other
+    /// proxies.
+    fn swap_in(&self, pool: &ProxyPool, routes: Vec<RouteSpec>) -> Result<()> {
+        let everything = self.paused.load(Ordering::Acquire);
+        pool.replace(|spec| everything || spec.source == Source::Hola, routes)?;
+        self.paused.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Endlessly loops, refreshing the Hola tunnels on a schedule and whenever requests through
+    /// the pool keep failing. The first refresh is sooner if we started with saved tunnels.
+    pub(crate) async fn refresh_loop(self: Arc<Self>, pool: Arc<ProxyPool>, interval: Duration) {
+        let mut wait = interval.saturating_sub(self.tunnels_age());
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => debug!("scheduled Hola refresh"),
+                _ = pool.troubled() => info!("proxy failing repeatedly, refreshing Hola tunnels"),
+            }
+            wait = interval;
+            if self.paused.load(Ordering::Acquire) {
+                debug!("Hola is paused, not refreshing");
+                continue;
+            }
+            let mut backoff = MIN_BACKOFF;
+            while let Err(e) = self.refresh(&pool).await {
+                error!("Hola refresh failed, retrying in {backoff:?}: {e:#}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// A country's route once it's set up, and when that was.
+type CountrySlot = OnceCell<(Arc<Route>, Instant)>;
+
+/// Routes in countries picked per request, rather than the `--country` list. Each is set up the
+/// first time its country is asked for, and kept until it fails or is due a refresh.
+#[derive(Debug)]
+pub(crate) struct CountryRoutes {
+    hola: Arc<Hola>,
+    allowed: Vec<String>,
+    /// How long a route is kept. Forever if `None`.
+    ttl: Option<Duration>,
+    /// A route for each country and when it was set up. Requests for a country that's being
+    /// set up wait for it rather than fetching tunnels of their own; other countries don't wait.
+    routes: std::sync::Mutex<HashMap<String, Arc<CountrySlot>>>,
+}
+
+impl CountryRoutes {
+    pub(crate) fn new(hola: Arc<Hola>, opts: &Opts) -> Self {
+        Self {
+            hola,
+            allowed: opts.allowed_country.clone(),
+            ttl: (opts.hola_refresh_interval > 0)
+                .then(|| Duration::from_secs(opts.hola_refresh_interval)),
+            routes: std::sync::Mutex::default(),
+        }
+    }
+
+    pub(crate) fn allows(&self, country: &str) -> bool {
+        self.allowed.iter().any(|c| c == country)
+    }
+
+    /// A route in `country`, setting one up if there isn't one already.
+    pub(crate) async fn route(
+        &self,
+        country: &str,
+        settings: ClientSettings,
+    ) -> Result<Arc<Route>> {
+        let slot = {
+            let mut routes = self.routes.lock().unwrap();
+            let slot = routes.entry(country.to_owned()).or_default();
+            if let Some((_, created)) = slot.get()
+                && self.ttl.is_some_and(|ttl| created.elapsed() >= ttl)
+            {
+                *slot = Arc::default();
+            }
+            slot.clone()
+        };
+        // a failure leaves the slot empty, so the next request tries again
+        let (route, _) = slot
+            .get_or_try_init(|| async {
+                let spec = self.hola.route_in(country).await?;
+                info!("set up {} for requests asking for {country}", spec.label);
+                Ok::<_, anyhow::Error>((Arc::new(Route::new(spec, settings)?), Instant::now()))
+            })
+            .await?;
+        Ok(route.clone())
+    }
+
+    /// Forget every route, such as after logging in again.
+    pub(crate) fn clear(&self) {
+        self.routes.lock().unwrap().clear();
+    }
+
+    /// Forget `route` after it failed, so the next request for its country gets a new one.
+    pub(crate) fn evict(&self, route: &Arc<Route>) {
+        self.routes
+            .lock()
+            .unwrap()
+            .retain(|_, slot| !slot.get().is_some_and(|(r, _)| Arc::ptr_eq(r, route)));
+    }
+}
+
+/// Login to Hola, generating a new UUID unless one is provided.
+async fn login(ccgi: &Url, uuid: Option<Uuid>) -> Result<Session> {
+    let (bg, uuid) = hello::background_init(ccgi, uuid).await.context("Hola init")?;
+    match bg {
+        BgInitResponse::Success { key, .. } => Ok(Session { uuid, key }),
+        BgInitResponse::Block { .. } => Err(Blocked(bg).into()),
+    }
+}
+
+async fn fetch_tunnels(ccgi: &Url, session: &Session, country: &str) -> Result<TunnelResponse> {
+    let tunnels =
+        hello::get_tunnels(ccgi, &session.uuid, session.key, country, ProxyType::Direct, 3).await?;
     debug!("{:?}", tunnels);
-    let login = hello::uuid_to_login(&uuid);
-    let password = tunnels.agent_key;
+    Ok(tunnels)
+}
+
+/// A route through one of the `tunnels` for `country`.
+fn route_through(session: &Session, country: &str, tunnels: &TunnelResponse) -> Result<RouteSpec> {
+    let proxy_type = ProxyType::Direct;
+    let login = hello::uuid_to_login(&session.uuid);
+    let password = &tunnels.agent_key;
     debug!("login: {}", login);
     debug!("password: {}", password);
-    let (hostname, ip) =
-        tunnels.ip_list.choose(&mut rng()).expect("no tunnels found in hola response");
+    let Some((hostname, ip)) = tunnels.ip_list.choose(&mut rng()) else {
+        bail!("no tunnels found in hola response");
+    };
     let port = proxy_type.get_port(&tunnels.port);
     let proxy = if !hostname.is_empty() {
         format!("https://{hostname}:{port}")
     } else {
         format!("http://{ip}:{port}")
     }; // does this check actually need to exist?
-    if !opts.discard_creds {
-        debug!(
-            "Saving Hola credentials to {}",
-            confy::get_configuration_file_path(CRATE_NAME, None)?.display()
-        );
-        confy::store(CRATE_NAME, None, &config)?;
+    let label = format!("hola/{country} ({proxy})");
+    let proxy = Proxy::all(proxy)?.basic_auth(&login, password);
+    Ok(RouteSpec {
+        proxy: Some(proxy),
+        source: Source::Hola,
+        label,
+        country: Some(country.to_owned()),
+        priority: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::{Duration, SystemTime};
+
+    use uuid::Uuid;
+
+    use crate::hello::{PortMap, TunnelResponse};
+    use crate::hello_config::{
+        Config, SavedSession, SavedTunnels, Session, route_through, timestamp,
+    };
+
+    fn tunnels() -> TunnelResponse {
+        TunnelResponse {
+            agent_key: "agentkey".to_owned(),
+            agent_types: HashMap::new(),
+            ip_list: vec![(String::new(), "192.0.2.1".to_owned())],
+            port: PortMap { direct: 22222, hola: 0, peer: 0, trial: 0, trial_peer: 0 },
+            protocol: HashMap::new(),
+            vendor: HashMap::new(),
+            ztun: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn saved_state_expires() {
+        let now = SystemTime::now();
+        let hour_ago = timestamp(now) - 3600;
+        let uuid = Uuid::new_v4();
+        let config = Config {
+            uuid: Some(uuid),
+            session: Some(SavedSession { key: 12345, logged_in: hour_ago }),
+            tunnels: HashMap::from([(
+                "ru".to_owned(),
+                SavedTunnels { fetched: hour_ago, response: tunnels() },
+            )]),
+        };
+        let session = config.session(Duration::from_secs(2 * 3600), now).unwrap();
+        assert_eq!((session.uuid, session.key), (uuid, 12345));
+        assert!(config.session(Duration::from_secs(1800), now).is_none());
+        assert!(config.session(Duration::ZERO, now).is_none());
+        assert!(config.tunnels("ru", Some(Duration::from_secs(2 * 3600)), now).is_some());
+        assert!(config.tunnels("ru", None, now).is_some());
+        assert!(config.tunnels("ru", Some(Duration::from_secs(1800)), now).is_none());
+        assert!(config.tunnels("ua", None, now).is_none());
+        // only the UUID is there in files from older versions
+        let old: Config = toml::from_str(&format!("uuid = \"{uuid}\"")).unwrap();
+        assert!(old.session(Duration::MAX, now).is_none());
+    }
+
+    #[test]
+    fn saved_state_roundtrips() {
+        let config = Config {
+            uuid: Some(Uuid::new_v4()),
+            session: Some(SavedSession { key: 12345, logged_in: 1_700_000_000 }),
+            tunnels: HashMap::from([(
+                "ru".to_owned(),
+                SavedTunnels { fetched: 1_700_000_000, response: tunnels() },
+            )]),
+        };
+        let loaded: Config = toml::from_str(&toml::to_string(&config).unwrap()).unwrap();
+        let saved = &loaded.tunnels["ru"].response;
+        assert_eq!(saved.ip_list, tunnels().ip_list);
+        let session = Session { uuid: loaded.uuid.unwrap(), key: 12345 };
+        let route = route_through(&session, "ru", saved).unwrap();
+        assert_eq!(route.label, "hola/ru (http://192.0.2.1:22222)");
+        assert_eq!(route.country.as_deref(), Some("ru"));
     }
-    Ok(Proxy::all(proxy)?.basic_auth(&login, &password))
 }
@@ -0,0 +1,255 @@
+//! Full HLS proxying, off by default. The master playlist's variant URIs are rewritten to point
+//! back at this server, which then fetches the media playlists through the pool as well, rather
+//! than the player fetching them from Twitch directly.
+
+use std::sync::Arc;
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use axum_extra::{TypedHeader, headers::UserAgent};
+use http::{HeaderMap, Response, header::HOST};
+use reqwest_middleware::ClientWithMiddleware as Client;
+use serde::Deserialize;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::error::{AppError, AppResult};
+use crate::m3u8::Playlist;
+use crate::pool::Route;
+use crate::relay::SEGMENT_ENDPOINT;
+use crate::{COUNTRY_PARAM, LState, Opts, StreamID, common, playlist, timed};
+
+pub(crate) const HLS_ENDPOINT: &str = "/hls/media.m3u8";
+const URL_PARAM: &str = "u";
+/// Marks a live stream's playlists, which get their ads stripped.
+const CHANNEL_PARAM: &str = "channel";
+
+#[derive(Debug)]
+pub(crate) struct Hls {
+    /// Where clients reach this server. Taken from each request's Host header if not set.
+    public_url: Option<Url>,
+    /// Scheme for URLs built from the Host header.
+    scheme: &'static str,
+    allowed_hosts: Vec<String>,
+}
+
+impl Hls {
+    pub(crate) fn new(opts: &Opts) -> Self {
+        #[cfg(feature = "tls")]
+        let scheme = if opts.tls_key.is_some() { "https" } else { "http" };
+        #[cfg(not(feature = "tls"))]
+        let scheme = "http";
+        Self {
+            public_url: opts.public_url.clone(),
+            scheme,
+            allowed_hosts: opts.hls_allowed_host.iter().map(|h| h.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// Where rewritten URIs for `path` should point, for a request with these headers.
+    pub(crate) fn endpoint(&self, headers: &HeaderMap, path: &str) -> AppResult<Url> {
+        let base = match &self.public_url {
+            Some(url) => url.clone(),
+            None => {
+                let host = headers
+                    .get(HOST)
+                    .and_then(|h| h.to_str().ok())
+                    .ok_or_else(|| AppError::BadRequest("missing Host header".to_owned()))?;
+                Url::parse(&format!("{}://{host}/", self.scheme))
+                    .map_err(|_| AppError::BadRequest("invalid Host header".to_owned()))?
+            }
+        };
+        Ok(base.join(path.trim_start_matches('/')).expect("endpoint is a valid path"))
+    }
+
+    /// Whether `url` is on a host media playlists are allowed to come from. Anything else would
+    /// turn this server into an open proxy.
+    fn allows(&self, url: &Url) -> bool {
+        host_allowed(&self.allowed_hosts, url)
+    }
+
+    /// Point the playlists in a master playlist for `sid` at `endpoint`, where their host is
+    /// allowed, to be fetched from `country` if one was asked for. Others are left as they are,
+    /// for the player to fetch directly.
+    pub(crate) fn rewrite_master(
+        &self,
+        m3u8: &mut Playlist,
+        endpoint: &Url,
+        sid: &StreamID,
+        country: Option<&str>,
+    ) {
+        m3u8.map_uris(|uri| {
+            if !Url::parse(uri).is_ok_and(|url| self.allows(&url)) {
+                return None;
+            }
+            let mut url = endpoint.clone();
+            let mut query = url.query_pairs_mut();
+            query.append_pair(URL_PARAM, uri);
+            if let StreamID::Live(channel) = sid {
+                query.append_pair(CHANNEL_PARAM, channel);
+            }
+            if let Some(country) = country {
+                query.append_pair(COUNTRY_PARAM, country);
+            }
+            drop(query);
+            Some(url.into())
+        })
+    }
+}
+
+/// Whether `url` is http(s) on one of `hosts` (lowercase) or a subdomain of one.
+pub(crate) fn host_allowed(hosts: &[String], url: &Url) -> bool {
+    let Some(host) = url.host_str() else {
+        return false;
+    };
+    let host = host.to_ascii_lowercase();
+    matches!(url.scheme(), "http" | "https")
+        && hosts.iter().any(|allowed| {
+            host == *allowed
+                || host.strip_suffix(allowed.as_str()).is_some_and(|sub| sub.ends_with('.'))
+        })
+}
+
+/// Resolve relative URIs against where the playlist came from, since it's about to be served
+/// from somewhere else. VOD playlists use relative segment URIs.
+fn absolutize(m3u8: &mut Playlist, source: &Url) {
+    m3u8.map_uris(|uri| {
+        if Url::parse(uri).is_ok() { None } else { source.join(uri).ok().map(Into::into) }
+    })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MediaQuery {
+    u: String,
+    channel: Option<String>,
+    country: Option<String>,
+}
+
+pub(crate) async fn media_playlist(
+    Query(query): Query<MediaQuery>,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    State(state): State<LState>,
+) -> Response<Body> {
+    #[cfg(feature = "metrics")]
+    crate::metrics::request("media");
+    proxy_media(query, ua, &headers, &state).await.into_response()
+}
+
+async fn proxy_media(
+    query: MediaQuery,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: &HeaderMap,
+    state: &LState,
+) -> AppResult<Response<Body>> {
+    let hls = state.hls.expect("route only exists in HLS proxy mode");
+    let url = Url::parse(&query.u)
+        .map_err(|e| AppError::BadRequest(format!("invalid playlist URL: {e}")))?;
+    if !hls.allows(&url) {
+        return Err(AppError::BadRequest("not a Twitch playlist URL".to_owned()));
+    }
+    let country = query.country.map(|c| c.to_ascii_lowercase());
+    if let Some(country) = &country
+        && !state.allows_country(country)
+    {
+        return Err(AppError::CountryNotAllowed(country.clone()));
+    }
+    let user_agent = common::get_user_agent(ua, state.user_agent.as_ref())?;
+    let attempt = |route: Arc<Route>| {
+        let (url, user_agent) = (&url, &user_agent);
+        async move { timed("media", fetch(&route.client, url, user_agent)).await }
+    };
+    let m3u8 = state.failover(country.as_deref(), attempt, |_| false).await?;
+    let mut m3u8 = match &query.channel {
+        Some(channel) => state.stripper.strip(channel, &url, m3u8),
+        None => m3u8,
+    };
+    absolutize(&mut m3u8, &url);
+    if let Some(relay) = state.relay {
+        relay.rewrite_segments(&mut m3u8, &hls.endpoint(headers, SEGMENT_ENDPOINT)?);
+    }
+    Ok(playlist(m3u8.to_string()))
+}
+
+async fn fetch(client: &Client, url: &Url, user_agent: &UserAgent) -> AppResult<Playlist> {
+    let response =
+        client.get(url.as_str()).header(http::header::USER_AGENT, user_agent.as_str()).send().await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::upstream_status("media", response.as_ref().ok().map(|r| r.status()));
+    let response = response?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(AppError::Upstream { stage: "media", status });
+    }
+    Ok(Playlist::parse(&response.text().await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use crate::StreamID;
+    use crate::hls::{Hls, absolutize};
+    use crate::m3u8::Playlist;
+
+    #[test]
+    fn rewrites_allowed_uris() {
+        let master = "#EXTM3U\r\n\
+            #EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",URI=\"https://a.hls.ttvnw.net/audio.m3u8\"\r\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=1\r\n\
+            https://a.hls.ttvnw.net/v1/playlist/x.m3u8?a=1&b=2\r\n\
+            #EXT-X-STREAM-INF:BANDWIDTH=2\r\n\
+            https://d1m7jfoe9zdc1j.cloudfront.net/abc/chunked/index-dvr.m3u8\r\n";
+        let endpoint = Url::parse("https://example.com/ttv/hls/media.m3u8").unwrap();
+        let hls =
+            Hls { public_url: None, scheme: "http", allowed_hosts: vec!["ttvnw.net".to_owned()] };
+        let mut rewritten = Playlist::parse(master);
+        hls.rewrite_master(&mut rewritten, &endpoint, &StreamID::VOD("1".to_owned()), Some("ru"));
+        let rewritten = rewritten.to_string();
+        let lines: Vec<_> = rewritten.split("\r\n").collect();
+        assert_eq!(lines[0], "#EXTM3U");
+        assert_eq!(
+            lines[1],
+            "#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",URI=\"https://example.com/ttv/hls/media.m3u8\
+                ?u=https%3A%2F%2Fa.hls.ttvnw.net%2Faudio.m3u8&country=ru\""
+        );
+        assert_eq!(
+            lines[3],
+            "https://example.com/ttv/hls/media.m3u8\
+                ?u=https%3A%2F%2Fa.hls.ttvnw.net%2Fv1%2Fplaylist%2Fx.m3u8%3Fa%3D1%26b%3D2&country=ru"
+        );
+        // not allowed, so the player fetches it itself
+        assert_eq!(lines[5], "https://d1m7jfoe9zdc1j.cloudfront.net/abc/chunked/index-dvr.m3u8");
+        assert_eq!(lines[6], "");
+    }
+
+    #[test]
+    fn resolves_relative_segments() {
+        let media = "#EXTM3U\n#EXTINF:10.000,\n0.ts\n#EXTINF:10.000,\nhttps://other.example/1.ts\n";
+        let source =
+            Url::parse("https://d1m7jfoe9zdc1j.cloudfront.net/abc/chunked/index-dvr.m3u8").unwrap();
+        let mut absolute = Playlist::parse(media);
+        absolutize(&mut absolute, &source);
+        assert_eq!(
+            absolute.to_string(),
+            "#EXTM3U\n#EXTINF:10.000,\nhttps://d1m7jfoe9zdc1j.cloudfront.net/abc/chunked/0.ts\n\
+                #EXTINF:10.000,\nhttps://other.example/1.ts\n"
+        );
+    }
+
+    #[test]
+    fn only_allowed_hosts() {
+        let hls =
+            Hls { public_url: None, scheme: "http", allowed_hosts: vec!["ttvnw.net".to_owned()] };
+        let allows = |url: &str| hls.allows(&Url::parse(url).unwrap());
+        assert!(allows("https://video-weaver.arn03.hls.ttvnw.net/v1/playlist/x.m3u8"));
+        assert!(allows("https://ttvnw.net/x.m3u8"));
+        assert!(!allows("https://evilttvnw.net/x.m3u8"));
+        assert!(!allows("https://ttvnw.net.evil.example/x.m3u8"));
+        assert!(!allows("file:///etc/passwd"));
+    }
+}
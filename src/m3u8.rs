@@ -0,0 +1,478 @@
+//! Twitch's master and media playlists, parsed into lines of typed tags. Only what's needed here
+//! is understood; everything else is kept as it was. A line is only parsed if writing it back
+//! gives exactly the same text, so a playlist nothing was changed in comes out byte for byte.
+
+use std::fmt::{self, Write};
+
+/// A playlist, one entry per line.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Playlist {
+    pub(crate) lines: Vec<Line>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Line {
+    pub(crate) item: Item,
+    /// `"\n"`, `"\r\n"`, or nothing at the very end.
+    pub(crate) ending: &'static str,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Item {
+    /// `#EXT-X-TWITCH-INFO`, in master playlists.
+    TwitchInfo(TwitchInfo),
+    /// `#EXT-X-SESSION-DATA`, in master playlists.
+    SessionData(Attributes),
+    /// `#EXT-X-MEDIA`, in master playlists.
+    Media(Media),
+    /// `#EXT-X-STREAM-INF`, followed by the variant's URI.
+    StreamInf(StreamInf),
+    /// `#EXT-X-DATERANGE`, which Twitch uses to mark ad breaks among other things.
+    DateRange(Attributes),
+    /// Any other tag with an attribute list, like `#EXT-X-KEY` or `#EXT-X-MAP`.
+    Tag { name: String, attributes: Attributes },
+    /// `#EXT-X-MEDIA-SEQUENCE`
+    MediaSequence(u64),
+    /// `#EXT-X-DISCONTINUITY-SEQUENCE`
+    DiscontinuitySequence(u64),
+    /// `#EXT-X-DISCONTINUITY`
+    Discontinuity,
+    /// `#EXTINF`, describing the segment after it.
+    Inf(Inf),
+    /// `#EXT-X-TWITCH-PREFETCH`, a segment that isn't out yet.
+    Prefetch(String),
+    /// A segment's or variant's URI.
+    Uri(String),
+    /// Anything else, including blank lines, as it was.
+    Other(String),
+}
+
+/// The duration and title of a segment.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Inf {
+    /// As written, so it comes out the same.
+    pub(crate) duration: String,
+    pub(crate) title: Option<String>,
+}
+
+/// A tag's attributes, in order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct Attributes(Vec<(String, Value)>);
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    Quoted(String),
+    /// Numbers, enumerated strings, resolutions and so on.
+    Plain(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TwitchInfo(pub(crate) Attributes);
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Media(pub(crate) Attributes);
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct StreamInf(pub(crate) Attributes);
+
+const TWITCH_INFO: &str = "#EXT-X-TWITCH-INFO";
+const SESSION_DATA: &str = "#EXT-X-SESSION-DATA";
+const MEDIA: &str = "#EXT-X-MEDIA";
+const STREAM_INF: &str = "#EXT-X-STREAM-INF";
+const DATERANGE: &str = "#EXT-X-DATERANGE";
+const MEDIA_SEQUENCE: &str = "#EXT-X-MEDIA-SEQUENCE";
+const DISCONTINUITY_SEQUENCE: &str = "#EXT-X-DISCONTINUITY-SEQUENCE";
+const DISCONTINUITY: &str = "#EXT-X-DISCONTINUITY";
+const INF: &str = "#EXTINF";
+const PREFETCH: &str = "#EXT-X-TWITCH-PREFETCH";
+const USER_IP: &str = "USER-IP";
+
+impl Playlist {
+    pub(crate) fn parse(m3u8: &str) -> Self {
+        let lines = m3u8
+            .split_inclusive('\n')
+            .map(|line| {
+                let content = line.trim_end_matches(['\r', '\n']);
+                let ending = match &line[content.len()..] {
+                    "\r\n" => "\r\n",
+                    "\n" => "\n",
+                    "" => "",
+                    _ => unreachable!("split on \\n, and only \\r and \\n were trimmed"),
+                };
+                Line { item: Item::parse(content), ending }
+            })
+            .collect();
+        Self { lines }
+    }
+
+    /// The line ending most of the playlist uses, for adding lines.
+    pub(crate) fn ending(&self) -> &'static str {
+        self.lines.first().map_or("\n", |l| if l.ending.is_empty() { "\n" } else { l.ending })
+    }
+
+    pub(crate) fn twitch_info(&self) -> Option<&TwitchInfo> {
+        self.lines.iter().find_map(|l| match &l.item {
+            Item::TwitchInfo(info) => Some(info),
+            _ => None,
+        })
+    }
+
+    /// Replace the IP address Twitch saw the request come from, wherever it's given. Lines that
+    /// weren't parsed are searched as text, so an odd `EXT-X-TWITCH-INFO` can't slip through.
+    #[cfg_attr(not(feature = "redact-ip"), allow(dead_code))] // only used to redact
+    pub(crate) fn set_user_ip(&mut self, ip: &str) {
+        for line in &mut self.lines {
+            match &mut line.item {
+                Item::TwitchInfo(info) => info.0.set(USER_IP, ip),
+                Item::Other(text) if text.contains(USER_IP) => {
+                    *text = replace_values(text, USER_IP, ip);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// `EXT-X-SESSION-DATA` tags.
+    pub(crate) fn session_data(&self) -> impl Iterator<Item = &Attributes> {
+        self.lines.iter().filter_map(|l| match &l.item {
+            Item::SessionData(data) => Some(data),
+            _ => None,
+        })
+    }
+
+    /// Apply `f` to every URI, both on their own lines and in `URI` attributes. URIs that `f`
+    /// returns `None` for are left alone.
+    pub(crate) fn map_uris(&mut self, f: impl Fn(&str) -> Option<String>) {
+        for line in &mut self.lines {
+            let uri = match &mut line.item {
+                Item::Uri(uri) | Item::Prefetch(uri) => uri,
+                Item::Tag { attributes, .. }
+                | Item::Media(Media(attributes))
+                | Item::SessionData(attributes) => match attributes.get_mut("URI") {
+                    Some(uri) => uri,
+                    None => continue,
+                },
+                _ => continue,
+            };
+            if let Some(new) = f(uri) {
+                *uri = new;
+            }
+        }
+    }
+}
+
+impl fmt::Display for Playlist {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            write!(f, "{}{}", line.item, line.ending)?;
+        }
+        Ok(())
+    }
+}
+
+impl Item {
+    fn parse(content: &str) -> Self {
+        let parsed = Self::parse_loosely(content);
+        // anything odd, like spacing, would be lost by writing it back out; keep it as it was
+        match parsed {
+            Some(item) if item.to_string() == content => item,
+            _ => Self::Other(content.to_owned()),
+        }
+    }
+
+    fn parse_loosely(content: &str) -> Option<Self> {
+        if !content.starts_with('#') {
+            return (!content.trim().is_empty()).then(|| Self::Uri(content.to_owned()));
+        }
+        let (name, value) = match content.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (content, None),
+        };
+        Some(match (name, value) {
+            (DISCONTINUITY, None) => Self::Discontinuity,
+            (_, None) => return None,
+            (TWITCH_INFO, Some(v)) => Self::TwitchInfo(TwitchInfo(Attributes::parse(v)?)),
+            (SESSION_DATA, Some(v)) => Self::SessionData(Attributes::parse(v)?),
+            (MEDIA, Some(v)) => Self::Media(Media(Attributes::parse(v)?)),
+            (STREAM_INF, Some(v)) => Self::StreamInf(StreamInf(Attributes::parse(v)?)),
+            (DATERANGE, Some(v)) => Self::DateRange(Attributes::parse(v)?),
+            (MEDIA_SEQUENCE, Some(v)) => Self::MediaSequence(v.parse().ok()?),
+            (DISCONTINUITY_SEQUENCE, Some(v)) => Self::DiscontinuitySequence(v.parse().ok()?),
+            (INF, Some(v)) => Self::Inf(match v.split_once(',') {
+                Some((duration, title)) => {
+                    Inf { duration: duration.to_owned(), title: Some(title.to_owned()) }
+                }
+                None => Inf { duration: v.to_owned(), title: None },
+            }),
+            (PREFETCH, Some(v)) => Self::Prefetch(v.to_owned()),
+            (name, Some(v)) => {
+                Self::Tag { name: name.to_owned(), attributes: Attributes::parse(v)? }
+            }
+        })
+    }
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TwitchInfo(TwitchInfo(a)) => write!(f, "{TWITCH_INFO}:{a}"),
+            Self::SessionData(a) => write!(f, "{SESSION_DATA}:{a}"),
+            Self::Media(Media(a)) => write!(f, "{MEDIA}:{a}"),
+            Self::StreamInf(StreamInf(a)) => write!(f, "{STREAM_INF}:{a}"),
+            Self::DateRange(a) => write!(f, "{DATERANGE}:{a}"),
+            Self::Tag { name, attributes } => write!(f, "{name}:{attributes}"),
+            Self::MediaSequence(n) => write!(f, "{MEDIA_SEQUENCE}:{n}"),
+            Self::DiscontinuitySequence(n) => write!(f, "{DISCONTINUITY_SEQUENCE}:{n}"),
+            Self::Discontinuity => f.write_str(DISCONTINUITY),
+            Self::Inf(Inf { duration, title: Some(title) }) => {
+                write!(f, "{INF}:{duration},{title}")
+            }
+            Self::Inf(Inf { duration, title: None }) => write!(f, "{INF}:{duration}"),
+            Self::Prefetch(uri) => write!(f, "{PREFETCH}:{uri}"),
+            Self::Uri(uri) | Self::Other(uri) => f.write_str(uri),
+        }
+    }
+}
+
+/// Replace every value of `name` in text that looks like an attribute list, quoted or not.
+#[cfg_attr(not(feature = "redact-ip"), allow(dead_code))]
+fn replace_values(text: &str, name: &str, value: &str) -> String {
+    let key = format!("{name}=");
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(at) = rest.find(&key) {
+        let (before, after) = rest.split_at(at + key.len());
+        out.push_str(before);
+        rest = match after.strip_prefix('"') {
+            Some(quoted) => {
+                out.push_str(&format!("\"{value}\""));
+                quoted.find('"').map_or("", |end| &quoted[end + 1..])
+            }
+            None => {
+                out.push_str(value);
+                &after[after.find(',').unwrap_or(after.len())..]
+            }
+        };
+    }
+    out.push_str(rest);
+    out
+}
+
+impl Attributes {
+    /// Parse an attribute list, if it is one. Quoted values may contain commas.
+    fn parse(list: &str) -> Option<Self> {
+        let mut attributes = Vec::new();
+        let mut rest = list;
+        while !rest.is_empty() {
+            let (name, after) = rest.split_once('=')?;
+            if name.is_empty()
+                || !name.bytes().all(|b| b.is_ascii_uppercase() || b.is_ascii_digit() || b == b'-')
+            {
+                return None;
+            }
+            let (value, after) = match after.strip_prefix('"') {
+                Some(quoted) => {
+                    let (value, after) = quoted.split_once('"')?;
+                    (Value::Quoted(value.to_owned()), after)
+                }
+                None => {
+                    let end = after.find(',').unwrap_or(after.len());
+                    (Value::Plain(after[..end].to_owned()), &after[end..])
+                }
+            };
+            attributes.push((name.to_owned(), value));
+            rest = match after.strip_prefix(',') {
+                Some(next) if !next.is_empty() => next,
+                None if after.is_empty() => after,
+                _ => return None,
+            };
+        }
+        Some(Self(attributes))
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.0.iter().find(|(n, _)| n == name).map(|(_, v)| v.as_str())
+    }
+
+    pub(crate) fn names(&self) -> impl Iterator<Item = &str> {
+        self.0.iter().map(|(n, _)| n.as_str())
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut String> {
+        self.0.iter_mut().find(|(n, _)| n == name).map(|(_, v)| match v {
+            Value::Quoted(s) | Value::Plain(s) => s,
+        })
+    }
+
+    /// Change an attribute's value, if it's there.
+    #[cfg_attr(not(feature = "redact-ip"), allow(dead_code))]
+    pub(crate) fn set(&mut self, name: &str, value: &str) {
+        if let Some(v) = self.get_mut(name) {
+            *v = value.to_owned();
+        }
+    }
+}
+
+impl fmt::Display for Attributes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_char(',')?;
+            }
+            match value {
+                Value::Quoted(v) => write!(f, "{name}=\"{v}\"")?,
+                Value::Plain(v) => write!(f, "{name}={v}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Value {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Quoted(s) | Self::Plain(s) => s,
+        }
+    }
+}
+
+impl TwitchInfo {
+    /// The country Twitch believes the request came from.
+    pub(crate) fn user_country(&self) -> Option<&str> {
+        self.0.get("USER-COUNTRY")
+    }
+}
+
+impl Media {
+    pub(crate) fn group_id(&self) -> Option<&str> {
+        self.0.get("GROUP-ID")
+    }
+}
+
+impl StreamInf {
+    /// Peak bits per second.
+    pub(crate) fn bandwidth(&self) -> Option<u64> {
+        self.0.get("BANDWIDTH")?.parse().ok()
+    }
+
+    /// Width and height, which audio-only variants don't have.
+    pub(crate) fn resolution(&self) -> Option<(u32, u32)> {
+        let (width, height) = self.0.get("RESOLUTION")?.split_once('x')?;
+        Some((width.parse().ok()?, height.parse().ok()?))
+    }
+
+    pub(crate) fn codecs(&self) -> impl Iterator<Item = &str> {
+        self.0.get("CODECS").unwrap_or_default().split(',').map(str::trim).filter(|c| !c.is_empty())
+    }
+
+    /// Media groups it uses.
+    pub(crate) fn groups(&self) -> impl Iterator<Item = &str> {
+        ["VIDEO", "AUDIO"].into_iter().filter_map(|name| self.0.get(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::m3u8::{Inf, Item, Playlist};
+
+    const MASTER: &str = include_str!("../fixtures/master.m3u8");
+    const LIVE: &str = include_str!("../fixtures/live.m3u8");
+    const VOD: &str = include_str!("../fixtures/vod.m3u8");
+
+    #[test]
+    fn roundtrip() {
+        for fixture in [MASTER, LIVE, VOD] {
+            assert_eq!(Playlist::parse(fixture).to_string(), fixture);
+            let crlf = fixture.replace('\n', "\r\n");
+            assert_eq!(Playlist::parse(&crlf).to_string(), crlf);
+            let unterminated = fixture.trim_end();
+            assert_eq!(Playlist::parse(unterminated).to_string(), unterminated);
+        }
+        // not quite what's expected, but kept as it was
+        let odd = "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE: 5\n#EXT-X-KEY:METHOD=NONE, URI=\"x\"\n\n";
+        let playlist = Playlist::parse(odd);
+        assert!(matches!(playlist.lines[1].item, Item::Other(_)));
+        assert!(matches!(playlist.lines[2].item, Item::Other(_)));
+        assert_eq!(playlist.to_string(), odd);
+    }
+
+    #[test]
+    fn master() {
+        let playlist = Playlist::parse(MASTER);
+        let info = playlist.twitch_info().unwrap();
+        assert_eq!(info.user_country(), Some("RU"));
+        assert_eq!(info.0.get("USER-IP"), Some("203.0.113.7"));
+        let data: Vec<_> = playlist.session_data().filter_map(|d| d.get("DATA-ID")).collect();
+        assert_eq!(data, ["com.amazon.ad.preroll", "com.twitch.tv.node"]);
+        let variants: Vec<_> = playlist
+            .lines
+            .iter()
+            .filter_map(|l| match &l.item {
+                Item::StreamInf(inf) => Some(inf),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(variants.len(), 4);
+        assert_eq!(variants[0].bandwidth(), Some(8_534_000));
+        assert_eq!(variants[0].resolution(), Some((1920, 1080)));
+        assert_eq!(variants[0].codecs().collect::<Vec<_>>(), ["avc1.64002A", "mp4a.40.2"]);
+        assert_eq!(variants[0].groups().collect::<Vec<_>>(), ["chunked"]);
+        assert_eq!(variants[3].resolution(), None);
+        let names: Vec<_> = playlist
+            .lines
+            .iter()
+            .filter_map(|l| match &l.item {
+                Item::Media(media) => media.0.get("NAME"),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(names, ["1080p60 (source)", "720p60", "480p30", "audio_only"]);
+    }
+
+    #[test]
+    fn media() {
+        let playlist = Playlist::parse(LIVE);
+        let items: Vec<_> = playlist.lines.iter().map(|l| &l.item).collect();
+        assert!(items.contains(&&Item::MediaSequence(2000)));
+        assert!(items.contains(&&Item::DiscontinuitySequence(3)));
+        assert!(items.iter().any(|i| matches!(i, Item::DateRange(d)
+            if d.get("CLASS") == Some("twitch-stitched-ad") && d.get("X-TV-TWITCH-AD-URL").is_some())));
+        let titles: Vec<_> = items
+            .iter()
+            .filter_map(|i| match i {
+                Item::Inf(Inf { title, .. }) => title.as_deref(),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(titles, ["live", "Amazon|3141592", "Amazon|3141592", "live"]);
+        assert!(matches!(items.last(), Some(Item::Prefetch(_))));
+
+        // VOD segments are relative, and the map and key tags have URIs too
+        let mut playlist = Playlist::parse(VOD);
+        playlist.map_uris(|uri| Some(format!("https://vod.example/{uri}")));
+        let vod = playlist.to_string();
+        assert!(vod.contains("#EXT-X-MAP:URI=\"https://vod.example/init-0.mp4\"\n"));
+        assert!(vod.contains("\nhttps://vod.example/0.mp4\n"));
+        assert!(!vod.contains("\n1.mp4\n"));
+    }
+
+    #[test]
+    fn changes() {
+        let mut playlist = Playlist::parse(MASTER);
+        playlist.set_user_ip("1.1.1.1");
+        let changed = playlist.to_string();
+        assert_eq!(changed, MASTER.replace("203.0.113.7", "1.1.1.1"));
+    }
+
+    #[test]
+    fn redacts_unparsed_twitch_info() {
+        let odd = "#EXTM3U\n#EXT-X-TWITCH-INFO:NODE=\"x\", USER-IP=\"203.0.113.7\",B=USER-IP=2001:db8::1\n";
+        let mut playlist = Playlist::parse(odd);
+        assert!(playlist.twitch_info().is_none());
+        playlist.set_user_ip("1.1.1.1");
+        assert_eq!(
+            playlist.to_string(),
+            "#EXTM3U\n#EXT-X-TWITCH-INFO:NODE=\"x\", USER-IP=\"1.1.1.1\",B=USER-IP=1.1.1.1\n"
+        );
+    }
+}
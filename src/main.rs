@@ -1,9 +1,8 @@
-use std::borrow::Cow;
 use std::collections::HashMap;
 use std::net::{IpAddr, SocketAddr};
-#[cfg(feature = "tls")]
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use axum::{
@@ -16,10 +15,12 @@ use axum::{
 };
 use axum_extra::{TypedHeader, headers::UserAgent};
 use cfg_if::cfg_if;
-use clap::Parser;
+#[cfg(feature = "true-status")]
+use clap::{CommandFactory, error::ErrorKind};
+use clap::{Parser, Subcommand};
 use extend::ext;
 use http::{
-    HeaderValue, Response, StatusCode,
+    HeaderMap, HeaderValue, Response, StatusCode,
     header::{CACHE_CONTROL, USER_AGENT},
 };
 use rand::distr::Alphanumeric;
@@ -27,22 +28,62 @@ use rand::{RngExt, rng};
 use reqwest::{ClientBuilder, Proxy};
 use reqwest_middleware::ClientWithMiddleware as Client;
 use reqwest_retry::{RetryTransientMiddleware, policies::ExponentialBackoff};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use serde_json::json;
 use tower::ServiceBuilder;
 use tower_http::cors::{Any, CorsLayer};
 use tower_http::set_header::SetResponseHeaderLayer;
 #[allow(unused)]
-use tracing::{Level, debug, error, info, warn};
+use tracing::{debug, error, info, warn};
 use url::Url;
 
+use crate::access_log::AccessLog;
+use crate::ads::AdSign;
+use crate::coalesce::Coalescer;
+use crate::common::Upstreams;
+use crate::error::{AppError, AppResult, UsherError};
+#[cfg(feature = "hola")]
+use crate::hello_config::{CountryRoutes, Hola};
+use crate::hls::Hls;
+use crate::m3u8::Playlist;
+use crate::pool::{ProxyPool, Route, RouteSpec};
+use crate::ratelimit::RateLimiter;
+use crate::relay::Relay;
+use crate::strip::AdStripper;
+use crate::tokens::{CacheStats, TokenCache};
+use crate::variants::{Codec, VariantFilter};
+
+mod access_log;
+mod admin;
+mod ads;
+mod clip;
+mod coalesce;
 mod common;
+mod config;
+#[cfg(test)]
+mod e2e;
+mod error;
+mod fetch;
 #[cfg(feature = "hola")]
 mod hello;
 #[cfg(feature = "hola")]
 mod hello_config;
+mod hls;
+mod m3u8;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod pool;
+#[cfg(feature = "hola")]
+mod probe;
+mod ratelimit;
+mod relay;
+#[cfg_attr(not(any(feature = "hola", feature = "true-status")), allow(dead_code))]
+mod sample; // only the player query is used without either
 #[cfg(feature = "true-status")]
 mod status;
+mod strip;
+mod tokens;
+mod variants;
 
 const ID_PARAM: &str = "id";
 const VOD_ENDPOINT: &str = const_format::concatcp!("/vod/{", ID_PARAM, "}");
@@ -50,13 +91,31 @@ const LIVE_ENDPOINT: &str = const_format::concatcp!("/live/{", ID_PARAM, "}");
 /// TTV-LOL emulation
 const LIVE_TTVLOL_ENDPOINT: &str = const_format::concatcp!("/playlist/{", ID_PARAM, "}");
 // for Firefox only
+const COUNTRY_PARAM: &str = "country";
+const COUNTRY_VOD_ENDPOINT: &str =
+    const_format::concatcp!("/c/{", COUNTRY_PARAM, "}/vod/{", ID_PARAM, "}");
+const COUNTRY_LIVE_ENDPOINT: &str =
+    const_format::concatcp!("/c/{", COUNTRY_PARAM, "}/live/{", ID_PARAM, "}");
+const CLIP_ENDPOINT: &str = const_format::concatcp!("/clip/{", ID_PARAM, "}");
+const COUNTRY_CLIP_ENDPOINT: &str =
+    const_format::concatcp!("/c/{", COUNTRY_PARAM, "}/clip/{", ID_PARAM, "}");
 const STATUS_ENDPOINT: &str = "/stat/";
 const STATUS_TTVLOL_ENDPOINT: &str = "/ping"; // no trailing slash
-const CONCURRENCY_LIMIT: usize = 64;
+/// Set on playlists served despite signs of ads, to the kind of sign.
+const ADS_HEADER: &str = "x-luminous-ads";
+/// Country the playlist was fetched from, as Twitch reported it.
+const COUNTRY_HEADER: &str = "x-luminous-country";
+/// `--ad-free-country` value that turns the check off.
+const NO_COUNTRIES: &str = "none";
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug, PartialEq)]
 #[clap(version, about)]
 pub(crate) struct Opts {
+    /// TOML file setting any of these options, keyed by their long names (e.g.
+    /// `rate-limit = 30`). Command-line arguments and environment variables take precedence.
+    /// On Unix, SIGHUP re-reads it and applies what can be changed without a restart.
+    #[arg(long, env = "LUMINOUS_TTV_CONFIG", display_order = 1)]
+    config: Option<PathBuf>,
     /// Address for this server to listen on.
     #[arg(short, long, default_value = "127.0.0.1", env = "LUMINOUS_TTV_ADDR")]
     address: IpAddr,
@@ -65,33 +124,123 @@ pub(crate) struct Opts {
     server_port: u16,
     /// Connect directly to Twitch, without a proxy. Useful when running this server remotely
     /// in a country where Twitch doesn't serve ads.
-    #[cfg_attr(feature = "hola", arg(long, conflicts_with_all(&["proxy", "country"])))]
+    #[cfg_attr(
+        feature = "hola",
+        arg(long, conflicts_with_all(&["proxy", "proxy_list", "country"]))
+    )]
     #[cfg_attr(
         not(feature = "hola"),
-        arg(long, conflicts_with = "proxy", env = "LUMINOUS_TTV_NO_PROXY")
+        arg(long, conflicts_with_all(&["proxy", "proxy_list"]), env = "LUMINOUS_TTV_NO_PROXY")
     )]
     no_proxy: bool,
     /// Custom proxy to use, instead of Hola. Takes the form of 'scheme://host:port',
     /// where scheme is one of: http/https/socks4/socks4a/socks5/socks5h.
     /// Must be in a country where Twitch doesn't serve ads for this system to work.
+    /// Can be repeated to build a pool; requests fail over to the next healthy proxy.
     #[cfg_attr(feature = "hola", arg(short, long))]
     #[cfg_attr(
         not(feature = "hola"),
-        arg(short, long, required_unless_present = "no_proxy", env = "LUMINOUS_TTV_PROXY")
+        arg(
+            short,
+            long,
+            required_unless_present_any(&["no_proxy", "proxy_list"]),
+            env = "LUMINOUS_TTV_PROXY"
+        )
+    )]
+    proxy: Vec<Url>,
+    /// File containing proxy URLs to add to the pool, one per line. Lines starting with '#'
+    /// are ignored.
+    #[arg(long, env = "LUMINOUS_TTV_PROXY_LIST")]
+    proxy_list: Option<PathBuf>,
+    /// Seconds between health checks of each proxy in the pool. 0 disables health checks,
+    /// in which case a failed proxy is only retried once every proxy has failed.
+    #[arg(long, default_value = "60", env = "LUMINOUS_TTV_HEALTH_CHECK_INTERVAL")]
+    health_check_interval: u64,
+    /// Playlist requests allowed per minute from each client IP, after an initial burst.
+    /// IPv6 clients are grouped by /64. 0 disables per-client rate limiting.
+    #[arg(long, default_value = "60", env = "LUMINOUS_TTV_RATE_LIMIT", display_order = 4700)]
+    rate_limit: u32,
+    /// Requests a client can make at once before --rate-limit kicks in.
+    #[arg(long, default_value = "20", env = "LUMINOUS_TTV_RATE_LIMIT_BURST", display_order = 4701)]
+    rate_limit_burst: u32,
+    /// Address or CIDR range of a reverse proxy trusted to report the real client IP in the
+    /// Forwarded or X-Forwarded-For header. Can be repeated.
+    #[arg(long, value_parser = ratelimit::parse_cidr, display_order = 4702)]
+    #[arg(env = "LUMINOUS_TTV_TRUSTED_PROXIES", value_delimiter = ',')]
+    trusted_proxy: Vec<ipnet::IpNet>,
+    /// Playlist requests handled at once. Requests beyond this are rejected with HTTP 503.
+    #[arg(
+        long,
+        default_value = "64",
+        env = "LUMINOUS_TTV_CONCURRENCY_LIMIT",
+        display_order = 4600
+    )]
+    concurrency_limit: usize,
+    /// Seconds before a playlist request is abandoned with HTTP 504.
+    #[arg(long, default_value = "40", env = "LUMINOUS_TTV_REQUEST_TIMEOUT", display_order = 4601)]
+    request_timeout: u64,
+    /// Seconds before a single request to Twitch is abandoned.
+    #[arg(long, default_value = "20", env = "LUMINOUS_TTV_UPSTREAM_TIMEOUT", display_order = 4602)]
+    upstream_timeout: u64,
+    /// Milliseconds to wait before the first retry of a failed request to Twitch.
+    #[arg(long, default_value = "200", env = "LUMINOUS_TTV_RETRY_MIN_DELAY_MS")]
+    #[arg(display_order = 4603)]
+    retry_min_delay_ms: u64,
+    /// Longest wait between retries, in milliseconds.
+    #[arg(long, default_value = "3000", env = "LUMINOUS_TTV_RETRY_MAX_DELAY_MS")]
+    #[arg(display_order = 4604)]
+    retry_max_delay_ms: u64,
+    /// Seconds after which a failing request to Twitch is no longer retried. 0 disables retries.
+    #[arg(long, default_value = "15", env = "LUMINOUS_TTV_RETRY_DURATION", display_order = 4605)]
+    retry_duration: u64,
+    /// Seconds to keep a master playlist for other requests for the same stream. Requests
+    /// arriving while one is being fetched wait for it too. Requests served the same playlist
+    /// share its play session, so Twitch sees them as one viewer; 0 gives each its own.
+    #[arg(long, default_value = "3", env = "LUMINOUS_TTV_PLAYLIST_CACHE_TTL")]
+    #[arg(display_order = 4606)]
+    playlist_cache_ttl: u64,
+    /// Countries to request proxies in, most preferred first, such as `ru,ua,tr`. Requests fall
+    /// back to the next country when a proxy fails or Twitch places it somewhere else.
+    /// See https://client.hola.org/client_cgi/vpn_countries.json.
+    #[cfg(feature = "hola")]
+    #[arg(
+        short,
+        long,
+        conflicts_with_all(&["proxy", "proxy_list"]),
+        value_parser = parse_country,
+        value_delimiter = ',',
+        default_value = "ru"
     )]
-    proxy: Option<Url>,
-    /// Country to request a proxy in. See https://client.hola.org/client_cgi/vpn_countries.json.
+    country: Vec<String>,
+    /// Countries clients may ask for per request, with /c/COUNTRY/live/ID or `country=`. Hola
+    /// tunnels for them are set up on first use. Per-request countries are off if empty.
     #[cfg(feature = "hola")]
-    #[arg(short, long, conflicts_with = "proxy", value_parser = parse_country, default_value = "ru")]
-    country: String,
+    #[arg(long, value_parser = parse_country, value_delimiter = ',')]
+    #[arg(conflicts_with_all(&["proxy", "proxy_list"]), env = "LUMINOUS_TTV_ALLOWED_COUNTRIES")]
+    allowed_country: Vec<String>,
     /// Don't save Hola credentials.
     #[cfg(feature = "hola")]
-    #[arg(short, long, conflicts_with = "proxy")]
+    #[arg(short, long, conflicts_with_all(&["proxy", "proxy_list"]))]
     discard_creds: bool,
     /// Regenerate Hola credentials (don't load them).
     #[cfg(feature = "hola")]
-    #[arg(short, long, conflicts_with = "proxy")]
+    #[arg(short, long, conflicts_with_all(&["proxy", "proxy_list"]))]
     regen_creds: bool,
+    /// Seconds between fetching a fresh Hola tunnel. Tunnels are also refreshed early if
+    /// requests through them keep failing. 0 disables refreshing.
+    #[cfg(feature = "hola")]
+    #[arg(long, default_value = "1800", env = "LUMINOUS_TTV_HOLA_REFRESH_INTERVAL")]
+    hola_refresh_interval: u64,
+    /// Seconds a saved Hola session is reused across restarts before logging in again. Saved
+    /// tunnels are reused for --hola-refresh-interval. 0 logs in on every start.
+    #[cfg(feature = "hola")]
+    #[arg(long, default_value = "86400", env = "LUMINOUS_TTV_HOLA_SESSION_TTL")]
+    hola_session_ttl: u64,
+    /// Name to save the Hola session under, so several instances on one host don't share (and
+    /// overwrite) the same one.
+    #[cfg(feature = "hola")]
+    #[arg(long, value_parser = parse_profile, env = "LUMINOUS_TTV_PROFILE")]
+    profile: Option<String>,
     /// List Hola's available countries, for use with --country
     #[cfg(feature = "hola")]
     #[arg(long)]
@@ -106,11 +255,84 @@ pub(crate) struct Opts {
     tls_cert: Option<PathBuf>,
     #[cfg(feature = "true-status")]
     #[arg(long, env = "LUMINOUS_TTV_STATUS_SECRET")]
-    /// Secret for deep status endpoint, at /truestat/SECRET
-    status_secret: String,
+    /// Secret for deep status endpoint, at /truestat/SECRET. Required to run the server, but not
+    /// for subcommands.
+    status_secret: Option<String>,
+    /// Secret for the admin API, at /admin/SECRET, which can change proxies while running.
+    /// Disabled if not set.
+    #[arg(long, env = "LUMINOUS_TTV_ADMIN_SECRET")]
+    admin_secret: Option<String>,
+    /// Proxy media playlists too. Variant URIs in the master playlist are rewritten to point back
+    /// at this server, which fetches them through the proxy instead of the player going to
+    /// Twitch directly.
+    #[arg(long, env = "LUMINOUS_TTV_HLS_PROXY", display_order = 4900)]
+    hls_proxy: bool,
+    /// URL clients reach this server at, used for rewritten playlist URIs. Taken from the
+    /// request's Host header if not set; set it when running behind a reverse proxy.
+    #[arg(long, value_parser = common::parse_base_url, env = "LUMINOUS_TTV_PUBLIC_URL")]
+    #[arg(display_order = 4901)]
+    public_url: Option<Url>,
+    /// Hosts, and their subdomains, that media playlists may be fetched from in HLS proxy mode.
+    /// Playlists elsewhere are left for the player to fetch itself.
+    #[arg(long, default_value = "ttvnw.net,twitch.tv", value_delimiter = ',')]
+    #[arg(env = "LUMINOUS_TTV_HLS_ALLOWED_HOSTS", display_order = 4902)]
+    hls_allowed_host: Vec<String>,
+    /// Relay video segments through this server too, for networks where Twitch's CDN is slow.
+    /// Needs --hls-proxy.
+    #[arg(long, requires = "hls_proxy", env = "LUMINOUS_TTV_RELAY_SEGMENTS", display_order = 4910)]
+    relay_segments: bool,
+    /// Segments relayed at once. Separate from --concurrency-limit, which is for playlists.
+    #[arg(long, default_value_t = 32, env = "LUMINOUS_TTV_RELAY_CONCURRENCY")]
+    #[arg(display_order = 4911)]
+    relay_concurrency: usize,
+    /// Total bandwidth for relayed segments, in KiB/s. 0 means unlimited.
+    #[arg(long, default_value_t = 0, env = "LUMINOUS_TTV_RELAY_BANDWIDTH", display_order = 4912)]
+    relay_bandwidth: u64,
+    /// Hosts, and their subdomains, that segments may be relayed from. Only add hosts nobody else
+    /// can serve from, since anything under them can be fetched through this server.
+    #[arg(long, default_value = "ttvnw.net", value_delimiter = ',')]
+    #[arg(env = "LUMINOUS_TTV_RELAY_ALLOWED_HOSTS", display_order = 4913)]
+    relay_allowed_host: Vec<String>,
+    /// Highest resolution (height, like 720) to offer players. Requests can lower it with
+    /// `max_resolution=`.
+    #[arg(long, env = "LUMINOUS_TTV_MAX_RESOLUTION", display_order = 4920)]
+    max_resolution: Option<u32>,
+    /// Highest bitrate to offer players, in kbps. Requests can lower it with `max_bitrate=`.
+    #[arg(long, value_parser = clap::value_parser!(u64).range(..=u64::MAX / 1000))]
+    #[arg(env = "LUMINOUS_TTV_MAX_BITRATE", display_order = 4921)]
+    max_bitrate: Option<u64>,
+    /// Video codecs to offer players (av1, h265, h264). All of them if not set.
+    #[arg(long, value_delimiter = ',', env = "LUMINOUS_TTV_CODECS", display_order = 4922)]
+    codec: Vec<Codec>,
+    /// Resolutions to list first in master playlists, in order, like `720,480`. Players usually
+    /// start with the first one. Requests can override it with `prefer=`.
+    #[arg(long, value_delimiter = ',', env = "LUMINOUS_TTV_PREFER_RESOLUTIONS")]
+    #[arg(display_order = 4923)]
+    prefer_resolution: Vec<u32>,
+    /// Countries where Twitch doesn't serve ads. A proxy Twitch places anywhere else is treated
+    /// as failed. `none` turns the check off.
+    #[arg(long, value_parser = parse_ad_free_country, value_delimiter = ',')]
+    #[arg(default_value = "af,bd,kh,cn,ir,iq,il,ps,ru,sa,sy,th,tr,ua,ae")]
+    #[arg(env = "LUMINOUS_TTV_AD_FREE_COUNTRIES")]
+    ad_free_country: Vec<String>,
+    /// Fail with an `ads_detected` error when every proxy seems to get ads. By default such a
+    /// playlist is served anyway, with an X-Luminous-Ads header saying why it's suspect.
+    #[arg(long, env = "LUMINOUS_TTV_REJECT_ADS")]
+    reject_ads: bool,
     /// Debug logging.
     #[arg(long, display_order = 5000, env = "LUMINOUS_TTV_DEBUG")]
     debug: bool,
+    /// File to append an access log of playlist and segment requests to, or `-` for stdout.
+    #[arg(long, env = "LUMINOUS_TTV_ACCESS_LOG", display_order = 5010)]
+    access_log: Option<PathBuf>,
+    /// Access log format.
+    #[arg(long, value_enum, default_value = "json", env = "LUMINOUS_TTV_ACCESS_LOG_FORMAT")]
+    #[arg(display_order = 5011)]
+    access_log_format: access_log::Format,
+    /// How client IPs are anonymised in the access log. They're never logged in full.
+    #[arg(long, value_enum, default_value = "hash", env = "LUMINOUS_TTV_ACCESS_LOG_CLIENT_IP")]
+    #[arg(display_order = 5012)]
+    access_log_client_ip: access_log::ClientIp,
     /// Twitch client ID used to access the API. Default is the ID of the website.
     #[arg(long, default_value = "kimne78kx3ncx6brgo4mv6wki5h1ko", env = "LUMINOUS_TTV_CLIENT_ID")]
     twitch_client_id: String,
@@ -119,12 +341,48 @@ pub(crate) struct Opts {
     /// copy-or-default system.
     #[arg(short, long, env = "LUMINOUS_TTV_USER_AGENT")]
     user_agent: Option<HeaderValue>,
+    /// Twitch GQL endpoint.
+    #[arg(long, default_value = "https://gql.twitch.tv/gql", env = "LUMINOUS_TTV_GQL_URL")]
+    #[arg(display_order = 6000)]
+    gql_url: Url,
+    /// Base URL of Twitch's playlist server (usher).
+    #[arg(long, default_value = "https://usher.ttvnw.net/", env = "LUMINOUS_TTV_USHER_URL")]
+    #[arg(value_parser = common::parse_base_url, display_order = 6001)]
+    usher_url: Url,
+    /// Base URL of the Hola API.
+    #[cfg(feature = "hola")]
+    #[arg(long, default_value = hello::CCGI_URL, env = "LUMINOUS_TTV_HOLA_URL")]
+    #[arg(value_parser = common::parse_base_url, display_order = 6002)]
+    hola_url: Url,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Things to do instead of running the server.
+#[derive(Subcommand, Clone, Debug, PartialEq)]
+enum Command {
+    /// Print the master playlist of a live channel or VOD, or the URL of its best variant, and
+    /// exit. Exits with 3 if the channel is offline, 4 if the channel or VOD doesn't exist, and 5
+    /// if no proxy could get the playlist.
+    Fetch(fetch::FetchArgs),
+    /// Try playing a live stream through each Hola country, and report which ones Twitch serves
+    /// without ads. Takes the same Hola options as the server.
+    #[cfg(feature = "hola")]
+    Probe(probe::ProbeArgs),
 }
 
 // The "kimne..." client ID is shown in the clear if you load the main page.
 // Try `curl -s https://www.twitch.tv | tidy -q | grep 'clientId='`.
 
 #[cfg(feature = "hola")]
+fn parse_profile(input: &str) -> Result<String> {
+    // it becomes a file name
+    if input.is_empty() || !input.chars().all(|c| c.is_ascii_alphanumeric() || "-_".contains(c)) {
+        anyhow::bail!("Profile name must be letters, digits, '-' and '_': {}", input);
+    }
+    Ok(input.to_owned())
+}
+
 fn parse_country(input: &str) -> Result<String> {
     if input.len() != 2 {
         anyhow::bail!("Country argument invalid, must be 2 letters: {}", input);
@@ -132,55 +390,176 @@ fn parse_country(input: &str) -> Result<String> {
     Ok(input.to_ascii_lowercase())
 }
 
+fn parse_ad_free_country(input: &str) -> Result<String> {
+    if input.eq_ignore_ascii_case(NO_COUNTRIES) {
+        return Ok(NO_COUNTRIES.to_owned());
+    }
+    parse_country(input)
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let opts: Opts = Opts::parse();
+    let opts = match config::load(std::env::args_os()) {
+        Ok(opts) => opts,
+        Err(e) => match e.downcast::<clap::Error>() {
+            Ok(e) => e.exit(),
+            Err(e) => return Err(e),
+        },
+    };
     #[cfg(windows)]
     if let Err(code) = nu_ansi_term::enable_ansi_support() {
         error!("failed to enable ANSI support, error code {}", code);
     }
-    tracing_subscriber::fmt()
-        .with_max_level(if opts.debug { Level::DEBUG } else { Level::INFO })
-        .init();
+    #[allow(unused_variables)] // only reloaded on Unix
+    let log_level = config::init_logging(opts.debug, opts.command.is_some());
     #[cfg(feature = "hola")]
     if opts.list_countries {
-        return hello::list_countries().await;
+        return hello::list_countries(&opts.hola_url).await;
+    }
+    match opts.command.clone() {
+        #[cfg(feature = "hola")]
+        Some(Command::Probe(args)) => return probe::run(&opts, &args).await,
+        Some(Command::Fetch(args)) => std::process::exit(fetch::run(&opts, &args).await),
+        None => {}
+    }
+    #[cfg(feature = "true-status")]
+    if opts.status_secret.is_none() {
+        Opts::command()
+            .error(
+                ErrorKind::MissingRequiredArgument,
+                "--status-secret is required to run the server",
+            )
+            .exit();
+    }
+    let proxies = start_proxies(&opts).await?;
+    let state = LState::new(&opts, proxies)?;
+    #[cfg(unix)]
+    tokio::spawn(config::reload_on_sighup(opts.clone(), state.clone(), log_level));
+    let router = router(state, &opts);
+    let addr = SocketAddr::new(opts.address, opts.server_port);
+    info!("About to start listening on {addr}");
+    #[cfg(feature = "tls")]
+    if let (Some(key), Some(cert)) = (opts.tls_key, opts.tls_cert) {
+        let config = axum_server::tls_rustls::RustlsConfig::from_pem_file(&cert, &key).await?;
+        tokio::spawn(reload(config.clone(), key, cert));
+        return Ok(axum_server::bind_rustls(addr, config)
+            .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+            .await?);
     }
-    let proxy = if let Some(proxy) = opts.proxy {
-        let proxy = Proxy::all(proxy)?;
-        Some(proxy)
+    axum_server::bind(addr)
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+    Ok(())
+}
+
+/// The pool of proxies, and the Hola session behind it if there is one.
+struct Proxies {
+    pool: Arc<ProxyPool>,
+    #[cfg(feature = "hola")]
+    hola: Option<Arc<hello_config::Hola>>,
+}
+
+/// Build the pool of proxies from the options, and start its background tasks.
+async fn start_proxies(opts: &Opts) -> Result<Proxies> {
+    #[cfg(feature = "hola")]
+    let mut hola = None;
+    let routes = if opts.uses_custom_proxies() {
+        custom_routes(opts)?
     } else if opts.no_proxy {
-        None
+        vec![RouteSpec::direct()]
     } else {
-        hola_proxy(&opts).await?
+        cfg_if! {
+            if #[cfg(feature = "hola")] {
+                let session = hello_config::Hola::setup(opts).await?;
+                let routes = session.tunnels().await?;
+                hola = Some(Arc::new(session));
+                routes
+            } else {
+                unreachable!("how'd you get here") // checked earlier by clap in arg parsing
+            }
+        }
     };
-    let client = create_client(proxy.clone())?;
+    let pool = Arc::new(ProxyPool::new(routes, ClientSettings::from(opts))?);
+    if opts.health_check_interval > 0 {
+        let interval = Duration::from_secs(opts.health_check_interval);
+        tokio::spawn(pool.clone().health_check_loop(interval, opts.gql_url.clone()));
+    }
+    #[cfg(feature = "hola")]
+    if let Some(hola) = &hola
+        && opts.hola_refresh_interval > 0
+    {
+        let interval = Duration::from_secs(opts.hola_refresh_interval);
+        tokio::spawn(hola.clone().refresh_loop(pool.clone(), interval));
+    }
+    Ok(Proxies {
+        pool,
+        #[cfg(feature = "hola")]
+        hola,
+    })
+}
 
-    #[cfg(not(feature = "true-status"))]
-    let state = LState {
-        client,
-        twitch_client_id: Box::leak(opts.twitch_client_id.into_boxed_str()),
-        user_agent: opts.user_agent,
-    };
-    #[cfg(feature = "true-status")]
-    let state = LState {
-        client,
-        twitch_client_id: Box::leak(opts.twitch_client_id.into_boxed_str()),
-        user_agent: opts.user_agent,
-        proxy,
-    };
-    // TODO: Find some better way to do this
+/// Routes for `--proxy` and `--proxy-list`.
+fn custom_routes(opts: &Opts) -> Result<Vec<RouteSpec>> {
+    let mut proxies = opts.proxy.clone();
+    if let Some(path) = &opts.proxy_list {
+        proxies.extend(pool::read_proxy_list(path)?);
+    }
+    proxies.iter().map(RouteSpec::from_url).collect()
+}
+
+impl Opts {
+    fn uses_custom_proxies(&self) -> bool {
+        !self.proxy.is_empty() || self.proxy_list.is_some()
+    }
+}
+
+fn router(state: LState, opts: &Opts) -> Router {
+    #[allow(unused_mut)] // feature-gated
+    let mut status_router = Router::new()
+        .route(STATUS_ENDPOINT, get(status))
+        .route(STATUS_TTVLOL_ENDPOINT, get(status)); // all TTV-LOL cares about is HTTP 200
+    #[cfg(feature = "metrics")]
+    {
+        status_router = status_router.route(metrics::METRICS_ENDPOINT, get(metrics::metrics));
+    }
+    let mut status_router = status_router.with_state(state.clone());
+    // outside the concurrency limit too, so proxies can be fixed while overloaded
+    if let Some(secret) = &opts.admin_secret {
+        status_router = status_router.merge(admin::router(secret, state.clone()));
+    }
+    // segments have their own limits, and shouldn't be compressed or counted against playlists
+    let relay_router = state.relay.is_some().then(|| {
+        Router::new()
+            .route(relay::SEGMENT_ENDPOINT, get(relay::segment))
+            .with_state(state.clone())
+            .layer(axum::middleware::from_fn_with_state(state.clone(), access_log::record))
+    });
+    // players re-poll media playlists every few seconds, which would use up a client's
+    // playlist limit within a minute
+    let hls_router = state.hls.is_some().then(|| {
+        let router = Router::new()
+            .route(hls::HLS_ENDPOINT, get(hls::media_playlist))
+            .with_state(state.clone());
+        #[cfg(feature = "gzip")]
+        let router = router.layer(tower_http::compression::CompressionLayer::new());
+        router.layer(axum::middleware::from_fn_with_state(state.clone(), access_log::record))
+    });
 
     #[allow(unused_mut)] // feature-gated
     let mut router = Router::new()
         .route(VOD_ENDPOINT, get(process_vod))
         .route(LIVE_ENDPOINT, get(process_live))
-        .route(LIVE_TTVLOL_ENDPOINT, get(process_live));
+        .route(LIVE_TTVLOL_ENDPOINT, get(process_live))
+        .route(COUNTRY_VOD_ENDPOINT, get(process_vod_in))
+        .route(COUNTRY_LIVE_ENDPOINT, get(process_live_in))
+        .route(CLIP_ENDPOINT, get(clip::process_clip))
+        .route(COUNTRY_CLIP_ENDPOINT, get(clip::process_clip_in));
     #[cfg(feature = "true-status")]
-    {
-        router =
-            router.route(&format!("/truestat/{}", opts.status_secret), get(status::deep_status));
+    if let Some(secret) = &opts.status_secret {
+        router = router.route(&format!("/truestat/{secret}"), get(status::deep_status));
     }
+    let limiter = state.limiter.clone();
+    let logged = state.clone();
     let mut router = router.with_state(state);
     #[cfg(feature = "gzip")]
     {
@@ -190,70 +569,237 @@ async fn main() -> Result<()> {
         ServiceBuilder::new()
             .layer(HandleErrorLayer::new(handle_error))
             .load_shed()
-            .concurrency_limit(CONCURRENCY_LIMIT)
-            .timeout(Duration::from_secs(40))
+            .concurrency_limit(opts.concurrency_limit)
+            .timeout(Duration::from_secs(opts.request_timeout))
             .into_inner(),
     ); // rudimentary global rate-limiting, plus failsafe timeout
-    // TODO: Investigate IP-based rate-limiting (tower_governor). Remember to have configurable
-    //  code for trusting the reverse proxy, etc. Remember to limit by /64 for v6.
+    // outside the concurrency limit, so clients over their limit don't take up a slot
+    router = router.layer(axum::middleware::from_fn_with_state(limiter, ratelimit::limit));
+    // outermost, outside both limits, so requests they reject are logged too; status, metrics
+    // and admin endpoints aren't logged
+    router = router.layer(axum::middleware::from_fn_with_state(logged, access_log::record));
 
     // NOTE! Concurrency limit layer must be below (in layer terms, or before in code terms)
     // status endpoints! Otherwise, the tiny status endpoint uses up all the rate-limit available.
+    // Same goes for metrics, which should keep working when the server is overloaded.
 
-    router = router
-        .route(STATUS_ENDPOINT, get(status))
-        .route(STATUS_TTVLOL_ENDPOINT, get(status)) // all TTV-LOL cares about is HTTP 200
-        .layer(CorsLayer::new().allow_origin(Any))
-        .layer(SetResponseHeaderLayer::overriding(
+    let mut router = router.merge(status_router);
+    if let Some(hls) = hls_router {
+        router = router.merge(hls);
+    }
+    let router =
+        router.layer(CorsLayer::new().allow_origin(Any)).layer(SetResponseHeaderLayer::overriding(
             CACHE_CONTROL,
             HeaderValue::from_static("no-cache, no-store"),
         ));
-    let addr = SocketAddr::new(opts.address, opts.server_port);
-    info!("About to start listening on {addr}");
-    #[cfg(feature = "tls")]
-    if let (Some(key), Some(cert)) = (opts.tls_key, opts.tls_cert) {
-        let config = axum_server::tls_rustls::RustlsConfig::from_pem_file(&cert, &key).await?;
-        tokio::spawn(reload(config.clone(), key, cert));
-        return Ok(axum_server::bind_rustls(addr, config)
-            .serve(router.into_make_service())
-            .await?);
+    match relay_router {
+        Some(relay) => router.merge(relay.layer(CorsLayer::new().allow_origin(Any))),
+        None => router,
     }
-    axum_server::bind(addr).serve(router.into_make_service()).await?;
-    Ok(())
 }
 
 #[derive(Clone, Debug)]
 struct LState {
-    client: Client,
+    pool: Arc<ProxyPool>,
+    tokens: Arc<TokenCache>,
+    /// Master playlists being fetched or fetched moments ago.
+    playlists: Arc<Coalescer<Fetched>>,
+    limiter: Arc<RateLimiter>,
     twitch_client_id: &'static str,
     // changing CID during operation isn't supported, so just leak it as a pointless optimization
     user_agent: Option<HeaderValue>,
-    #[cfg(feature = "true-status")]
-    proxy: Option<Proxy>,
+    upstreams: &'static Upstreams, // same deal as the CID
+    reject_ads: bool,
+    /// Lowercase. Empty if not checked.
+    ad_free_countries: &'static [String],
+    /// Set in HLS proxy mode.
+    hls: Option<&'static Hls>,
+    /// Set when relaying segments.
+    relay: Option<&'static Relay>,
+    stripper: Arc<AdStripper>,
+    /// The server's limits on variants, which requests can tighten.
+    variants: &'static VariantFilter,
+    /// Set when using Hola.
+    #[cfg(feature = "hola")]
+    hola: Option<Arc<Hola>>,
+    /// Set when clients may pick a country per request.
+    #[cfg(feature = "hola")]
+    countries: Option<Arc<CountryRoutes>>,
+    access_log: Option<&'static AccessLog>,
 }
 
-#[cfg(feature = "hola")]
-async fn hola_proxy(opts: &Opts) -> Result<Option<Proxy>> {
-    let proxy = hello_config::setup_hola(opts).await?;
-    Ok(Some(proxy))
+impl LState {
+    fn new(opts: &Opts, proxies: Proxies) -> Result<Self> {
+        let upstreams = Upstreams { gql: opts.gql_url.clone(), usher: opts.usher_url.clone() };
+        Ok(Self {
+            pool: proxies.pool,
+            tokens: Arc::default(),
+            playlists: Arc::new(Coalescer::new(Duration::from_secs(opts.playlist_cache_ttl))),
+            limiter: Arc::new(RateLimiter::new(
+                opts.rate_limit,
+                opts.rate_limit_burst,
+                opts.trusted_proxy.clone(),
+            )),
+            twitch_client_id: Box::leak(opts.twitch_client_id.clone().into_boxed_str()),
+            user_agent: opts.user_agent.clone(),
+            upstreams: Box::leak(Box::new(upstreams)),
+            reject_ads: opts.reject_ads,
+            ad_free_countries: if opts.ad_free_country.iter().any(|c| c == NO_COUNTRIES) {
+                &[]
+            } else {
+                opts.ad_free_country.clone().leak()
+            },
+            hls: opts.hls_proxy.then(|| &*Box::leak(Box::new(Hls::new(opts)))),
+            relay: opts.relay_segments.then(|| &*Box::leak(Box::new(Relay::new(opts)))),
+            stripper: Arc::default(),
+            variants: Box::leak(Box::new(VariantFilter::new(opts))),
+            #[cfg(feature = "hola")]
+            hola: proxies.hola.clone(),
+            #[cfg(feature = "hola")]
+            countries: proxies
+                .hola
+                .filter(|_| !opts.allowed_country.is_empty())
+                .map(|hola| Arc::new(CountryRoutes::new(hola, opts))),
+            access_log: AccessLog::open(opts)?.map(|log| &*Box::leak(Box::new(log))),
+        })
+    }
+
+    fn allows_country(&self, country: &str) -> bool {
+        cfg_if! {
+            if #[cfg(feature = "hola")] {
+                self.countries.as_ref().is_some_and(|c| c.allows(country))
+            } else {
+                let _ = country;
+                false
+            }
+        }
+    }
+
+    /// Routes to try for a request that asked for `country`: those already in the pool for it,
+    /// or else one set up just for it.
+    async fn routes_in(&self, country: &str) -> AppResult<Vec<Arc<Route>>> {
+        cfg_if! {
+            if #[cfg(feature = "hola")] {
+                let countries = self.countries.as_ref().expect("country was allowed");
+                let in_pool: Vec<_> = self
+                    .pool
+                    .routes()
+                    .into_iter()
+                    .filter(|r| r.spec.country.as_deref() == Some(country))
+                    .collect();
+                if !in_pool.is_empty() {
+                    return Ok(in_pool);
+                }
+                let route = countries.route(country, self.pool.client_settings()).await.map_err(
+                    |e| AppError::ProxyUnreachable(format!("no Hola tunnel for {country}: {e:#}")),
+                )?;
+                Ok(vec![route])
+            } else {
+                unreachable!("no countries are allowed without Hola ({country})")
+            }
+        }
+    }
+
+    /// Routes to try for a request that may have asked for a country, in order.
+    async fn routes_for(&self, country: Option<&str>) -> AppResult<Vec<Arc<Route>>> {
+        match country {
+            Some(country) => self.routes_in(country).await,
+            None => Ok(self.pool.routes()),
+        }
+    }
+
+    /// Note that a route failed.
+    async fn route_failed(&self, route: &Arc<Route>) {
+        self.pool.failed(route);
+        #[cfg(feature = "hola")]
+        if let Some(countries) = &self.countries {
+            countries.evict(route);
+        }
+    }
+
+    /// Run `attempt` through each route for `country` in turn, moving on to the next when a
+    /// route fails, until one gives an answer. An answer that's `worse` also moves on, but is
+    /// returned if no other route does better.
+    async fn failover<T, F>(
+        &self,
+        country: Option<&str>,
+        mut attempt: impl FnMut(Arc<Route>) -> F,
+        worse: impl Fn(&T) -> bool,
+    ) -> AppResult<T>
+    where
+        F: Future<Output = AppResult<T>>,
+    {
+        let mut last_error = None;
+        let mut fallback = None;
+        for route in self.routes_for(country).await? {
+            access_log::via(route.label());
+            match attempt(route.clone()).await {
+                Ok(answer) => {
+                    self.pool.succeeded(&route);
+                    if !worse(&answer) {
+                        return Ok(answer);
+                    }
+                    fallback = Some(answer);
+                }
+                Err(e) if e.is_route_failure() => {
+                    warn!("request via {} failed, trying next proxy: {e}", route.label());
+                    self.route_failed(&route).await;
+                    last_error = Some(e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        fallback.ok_or_else(|| {
+            last_error.unwrap_or_else(|| AppError::ProxyUnreachable("no proxies available".into()))
+        })
+    }
+}
+
+/// Timeout and retry policy for requests to Twitch.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub(crate) struct ClientSettings {
+    timeout: Duration,
+    retry_min_delay: Duration,
+    retry_max_delay: Duration,
+    retry_duration: Duration,
 }
 
-#[cfg(not(feature = "hola"))]
-async fn hola_proxy(_opts: &Opts) -> Result<Option<Proxy>> {
-    unreachable!("how'd you get here") // checked earlier by clap in arg parsing
+impl From<&Opts> for ClientSettings {
+    fn from(opts: &Opts) -> Self {
+        Self {
+            timeout: Duration::from_secs(opts.upstream_timeout),
+            retry_min_delay: Duration::from_millis(opts.retry_min_delay_ms),
+            retry_max_delay: Duration::from_millis(opts.retry_max_delay_ms),
+            retry_duration: Duration::from_secs(opts.retry_duration),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(20),
+            retry_min_delay: Duration::from_millis(200),
+            retry_max_delay: Duration::from_secs(3),
+            retry_duration: Duration::from_secs(15),
+        }
+    }
 }
 
-pub(crate) fn create_client(proxy: Option<Proxy>) -> Result<Client> {
-    let mut cb = ClientBuilder::new().timeout(Duration::from_secs(20));
+pub(crate) fn create_client(proxy: Option<Proxy>, settings: ClientSettings) -> Result<Client> {
+    let mut cb = ClientBuilder::new().timeout(settings.timeout);
     if let Some(proxy) = proxy {
         cb = cb.proxy(proxy);
     } else {
         cb = cb.no_proxy();
     }
-    let client = cb.build()?;
+    // Twitch doesn't redirect these requests, and following a redirect from a media playlist URL
+    // could lead anywhere, not just to an allowed host
+    let client = cb.redirect(reqwest::redirect::Policy::none()).build()?;
     let backoff = ExponentialBackoff::builder()
-        .retry_bounds(Duration::from_millis(200), Duration::from_secs(3))
-        .build_with_total_retry_duration(Duration::from_secs(15));
+        .retry_bounds(settings.retry_min_delay, settings.retry_max_delay)
+        .build_with_total_retry_duration(settings.retry_duration);
     let client = reqwest_middleware::ClientBuilder::new(client)
         .with(RetryTransientMiddleware::new_with_policy(backoff))
         .build();
@@ -276,25 +822,31 @@ async fn reload(config: axum_server::tls_rustls::RustlsConfig, key: PathBuf, cer
 #[derive(Copy, Clone, Debug, Serialize)]
 struct Status {
     online: bool,
+    token_cache: CacheStats,
 }
 
 // in Chrome-like browsers the extension can download the M3U8, and if that succeeds redirect
 // to it in Base64 form. In Firefox that isn't permitted. Checking if the server is online before
 // redirecting to it reduces the chance of the extension breaking Twitch.
 // XXX note to self: Can I override CORS via the extension to fix the redirect?
+async fn status(State(state): State<LState>) -> Response<Body> {
+    let online = state.pool.is_ready() && deep_status_ok();
+    let status = Status { online, token_cache: state.tokens.stats() };
+    if online {
+        (StatusCode::OK, Json(status)).into_response()
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(status)).into_response()
+    }
+}
+
 cfg_if! {
     if #[cfg(feature = "true-status")] {
-        async fn status() -> Response<Body> {
-            let online = status::STATUS.load(std::sync::atomic::Ordering::Acquire);
-            if online {
-                (StatusCode::OK, Json(Status { online })).into_response()
-            } else {
-                (StatusCode::SERVICE_UNAVAILABLE, Json(Status { online })).into_response()
-            }
+        fn deep_status_ok() -> bool {
+            status::STATUS.load(std::sync::atomic::Ordering::Acquire)
         }
     } else {
-        async fn status() -> Json<Status> {
-            Json(Status { online: true })
+        fn deep_status_ok() -> bool {
+            true
         }
     }
 }
@@ -303,14 +855,22 @@ pub(crate) struct ProcessData {
     sid: StreamID,
     query: HashMap<String, String>,
     user_agent: UserAgent,
+    /// Where to point rewritten variant URIs, in HLS proxy mode.
+    hls_endpoint: Option<Url>,
+    /// Country the client asked for, if any; lowercase.
+    country: Option<String>,
+    /// Which variants to serve.
+    variants: VariantFilter,
 }
 
 impl ProcessData {
     fn build<F: FnOnce(String) -> StreamID>(
         id: String,
+        country: Option<String>,
         query: HashMap<String, String>,
         ua: Option<TypedHeader<UserAgent>>,
-        ua_override: Option<&HeaderValue>,
+        headers: &HeaderMap,
+        state: &LState,
         enum_type: F,
     ) -> AppResult<Self> {
         let (id, query) = if let Some((id, query)) = id.split_once(".m3u8?") {
@@ -318,15 +878,31 @@ impl ProcessData {
             // so this ignores the empty query map, splits out the query string, then
             // deserializes it to a map
             // (axum already did the first percent-decoding step)
-            let query: HashMap<String, String> =
-                serde_urlencoded::from_str(query).map_err(|e| AppError::Anyhow(e.into()))?;
-            (id.to_ascii_lowercase(), query)
+            let query: HashMap<String, String> = serde_urlencoded::from_str(query)
+                .map_err(|e| AppError::BadRequest(e.to_string()))?;
+            (id.to_owned(), query)
         } else {
             // normal path
-            (id.into_ascii_lowercase(), query)
+            (id, query)
         };
-        let user_agent = common::get_user_agent(ua, ua_override)?;
-        Ok(Self { sid: enum_type(id), query, user_agent })
+        let sid = match enum_type(id) {
+            StreamID::Live(id) => StreamID::Live(id.into_ascii_lowercase()),
+            StreamID::VOD(id) => StreamID::VOD(id.into_ascii_lowercase()),
+            clip @ StreamID::Clip(_) => clip,
+        };
+        access_log::stream(&sid);
+        let user_agent = common::get_user_agent(ua, state.user_agent.as_ref())?;
+        let hls_endpoint =
+            state.hls.map(|hls| hls.endpoint(headers, hls::HLS_ENDPOINT)).transpose()?;
+        let country =
+            country.or_else(|| query.get(COUNTRY_PARAM).cloned()).map(|c| c.to_ascii_lowercase());
+        if let Some(country) = &country
+            && !state.allows_country(country)
+        {
+            return Err(AppError::CountryNotAllowed(country.clone()));
+        }
+        let variants = state.variants.with_query(&query)?;
+        Ok(Self { sid, query, user_agent, hls_endpoint, country, variants })
     }
 }
 
@@ -342,61 +918,254 @@ async fn process_live(
     Path(id): Path<String>,
     Query(query): QueryMap,
     ua: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    State(state): State<LState>,
+) -> Response<Body> {
+    live(id, None, query, ua, &headers, &state).await
+}
+
+async fn process_live_in(
+    Path((country, id)): Path<(String, String)>,
+    Query(query): QueryMap,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
     State(state): State<LState>,
 ) -> Response<Body> {
-    let pd = match ProcessData::build(id, query, ua, state.user_agent.as_ref(), StreamID::Live) {
+    live(id, Some(country), query, ua, &headers, &state).await
+}
+
+async fn live(
+    id: String,
+    country: Option<String>,
+    query: HashMap<String, String>,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: &HeaderMap,
+    state: &LState,
+) -> Response<Body> {
+    #[cfg(feature = "metrics")]
+    metrics::request("live");
+    let pd = match ProcessData::build(id, country, query, ua, headers, state, StreamID::Live) {
         Ok(pd) => pd,
         Err(e) => return e.into_response(),
     };
-    process(pd, &state).await.into_response()
+    process(pd, state).await.into_response()
 }
 
 async fn process_vod(
     Path(id): Path<String>,
     Query(query): QueryMap,
     ua: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
+    State(state): State<LState>,
+) -> Response<Body> {
+    vod(id, None, query, ua, &headers, &state).await
+}
+
+async fn process_vod_in(
+    Path((country, id)): Path<(String, String)>,
+    Query(query): QueryMap,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: HeaderMap,
     State(state): State<LState>,
 ) -> Response<Body> {
-    let pd = match ProcessData::build(id, query, ua, state.user_agent.as_ref(), StreamID::VOD) {
+    vod(id, Some(country), query, ua, &headers, &state).await
+}
+
+async fn vod(
+    id: String,
+    country: Option<String>,
+    query: HashMap<String, String>,
+    ua: Option<TypedHeader<UserAgent>>,
+    headers: &HeaderMap,
+    state: &LState,
+) -> Response<Body> {
+    #[cfg(feature = "metrics")]
+    metrics::request("vod");
+    let pd = match ProcessData::build(id, country, query, ua, headers, state, StreamID::VOD) {
         Ok(pd) => pd,
         Err(e) => return e.into_response(),
     };
     if let StreamID::VOD(s) = &pd.sid
         && s.parse::<u64>().is_err()
     {
-        return StatusCode::BAD_REQUEST.into_response();
+        return AppError::BadRequest("VOD ID must be numeric".to_owned()).into_response();
     } // can't validate up front (which is cleaner) due to TTV-LOL emulation
-    process(pd, &state).await.into_response()
+    process(pd, state).await.into_response()
 }
 
 pub(crate) async fn process(pd: ProcessData, state: &LState) -> AppResult<Response<Body>> {
-    let token = get_token(state, &pd).await?;
-    let m3u8 = get_m3u8(&state.client, &pd, token.data.playback_access_token).await?;
-    Ok(([("Content-Type", "application/vnd.apple.mpegurl")], m3u8).into_response())
-}
-
-async fn get_m3u8(client: &Client, pd: &ProcessData, token: PlaybackAccessToken) -> Result<String> {
-    static PERMITTED_INCOMING_KEYS: phf::Set<&str> = phf::phf_set! {
-        "player_backend",             // mediaplayer
-        "playlist_include_framerate", // true
-        "reassignments_supported",    // true
-        "supported_codecs",           // av1,h265,h264
-        "cdm",                        // wv
-        "player_version",             // 1.36.0-rc.1
-        "fast_bread",                 // true; related to low latency mode
-        "allow_source",               // true
-        "allow_audio_only",           // true
-        "warp",                       // true; https://github.com/kixelated/warp-draft
-        "transcode_mode",             // cbr_v1
-        "platform",                   // web
+    let fetched = state.playlists.get(coalesce::Key::new(&pd), || fetch(&pd, state)).await?;
+    Ok(served(state, &pd, Fetched::clone(&fetched)))
+}
+
+/// Fetch the master playlist, failing over between routes.
+async fn fetch(pd: &ProcessData, state: &LState) -> AppResult<Fetched> {
+    let attempt = |route: Arc<Route>| async move {
+        let fetched = process_via(pd, state, &route).await;
+        let evict = match &fetched {
+            Ok(Fetched { ads: Some(sign), .. }) => {
+                warn!(
+                    "playlist via {} looks like it has ads ({sign}), trying next proxy",
+                    route.label()
+                );
+                #[cfg(feature = "metrics")]
+                metrics::ads_detected(sign.kind());
+                true
+            }
+            Err(AppError::WrongCountry { .. } | AppError::AdCountry(_)) => true,
+            _ => false,
+        };
+        if evict {
+            // tied to the proxy's IP, so it'll get the same again
+            state.tokens.evict(&pd.sid, route.label());
+        }
+        fetched
     };
-    // Some stuff that isn't permitted, but exists:
-    //  browser_family
-    //  browser_version
-    //  os_name
-    //  os_version
+    let fetched = state.failover(pd.country.as_deref(), attempt, |f| f.ads.is_some()).await?;
+    match fetched.ads {
+        Some(sign) if state.reject_ads => Err(AppError::AdsDetected(sign.to_string())),
+        _ => Ok(fetched),
+    }
+}
+
+/// Serve a playlist, recording where it came from, and marking it if it still looks like it has
+/// ads.
+fn served(state: &LState, pd: &ProcessData, fetched: Fetched) -> Response<Body> {
+    let mut response = master_playlist(state, pd, fetched.m3u8);
+    access_log::via(&fetched.via); // could have been fetched for another request
+    if let Some(country) = fetched.country {
+        debug!("served {:?} via {} in {country}", pd.sid, fetched.via);
+        access_log::country(&country);
+        #[cfg(feature = "metrics")]
+        metrics::served_country(&country);
+        if let Ok(value) = HeaderValue::from_str(&country) {
+            response.headers_mut().insert(COUNTRY_HEADER, value);
+        }
+    }
+    if let Some(sign) = fetched.ads {
+        response.headers_mut().insert(ADS_HEADER, HeaderValue::from_static(sign.kind()));
+    }
+    response
+}
+
+fn master_playlist(state: &LState, pd: &ProcessData, mut m3u8: Playlist) -> Response<Body> {
+    pd.variants.apply(&mut m3u8);
+    if let (Some(hls), Some(endpoint)) = (state.hls, &pd.hls_endpoint) {
+        hls.rewrite_master(&mut m3u8, endpoint, &pd.sid, pd.country.as_deref());
+    }
+    playlist(m3u8.to_string())
+}
 
-    let mut url = Url::parse(&pd.sid.get_url())?;
+fn playlist(m3u8: String) -> Response<Body> {
+    ([("Content-Type", "application/vnd.apple.mpegurl")], m3u8).into_response()
+}
+
+/// A playlist fetched through one route.
+#[derive(Clone, Debug)]
+struct Fetched {
+    m3u8: Playlist,
+    /// The route's label.
+    via: String,
+    /// Any sign that it comes with ads.
+    ads: Option<AdSign>,
+    /// Where Twitch says the route exits.
+    country: Option<String>,
+}
+
+impl Fetched {
+    fn new(
+        state: &LState,
+        route: &Route,
+        token: &PlaybackAccessToken,
+        mut m3u8: Playlist,
+    ) -> AppResult<Self> {
+        let expected = route.spec.country.as_ref().map(|c| c.to_ascii_uppercase());
+        let country = ads::user_country(&m3u8).map(str::to_ascii_uppercase);
+        // a playlist that doesn't say where the proxy is fails both checks, rather than skipping
+        // them
+        let unknown = || "an unknown country".to_owned();
+        if let Some(expected) = &expected
+            && country.as_ref() != Some(expected)
+        {
+            return Err(AppError::WrongCountry {
+                expected: expected.clone(),
+                actual: country.unwrap_or_else(unknown),
+            });
+        }
+        if !state.ad_free_countries.is_empty()
+            && !country
+                .as_ref()
+                .is_some_and(|c| state.ad_free_countries.iter().any(|a| a.eq_ignore_ascii_case(c)))
+        {
+            return Err(AppError::AdCountry(country.unwrap_or_else(unknown)));
+        }
+        let ads = ads::detect(token, &m3u8);
+        redact_ip(&mut m3u8);
+        Ok(Self { m3u8, via: route.label().to_owned(), ads, country })
+    }
+}
+
+/// Fetch the playlist through one route.
+async fn process_via(pd: &ProcessData, state: &LState, route: &Route) -> AppResult<Fetched> {
+    let client = &route.client;
+    if let Some(token) = state.tokens.get(&pd.sid, route.label()) {
+        match timed("m3u8", get_m3u8(client, state, pd, &token)).await {
+            Err(AppError::TokenRejected) => {
+                debug!("cached token for {:?} was rejected, fetching a new one", pd.sid);
+                state.tokens.evict(&pd.sid, route.label());
+            }
+            other => return other.and_then(|m3u8| Fetched::new(state, route, &token, m3u8)),
+        }
+    }
+    let token = timed("token", get_token(client, state, pd)).await?;
+    state.tokens.insert(pd.sid.clone(), route.label(), &token);
+    let m3u8 = timed("m3u8", get_m3u8(client, state, pd, &token)).await;
+    if let Err(AppError::TokenRejected) = &m3u8 {
+        state.tokens.evict(&pd.sid, route.label());
+    }
+    m3u8.and_then(|m3u8| Fetched::new(state, route, &token, m3u8))
+}
+
+/// Await an upstream request, recording how long it took.
+async fn timed<T>(stage: &'static str, request: impl Future<Output = T>) -> T {
+    let started = Instant::now();
+    let output = request.await;
+    let elapsed = started.elapsed();
+    debug!("{stage} took {elapsed:?}");
+    access_log::stage(stage, elapsed);
+    #[cfg(feature = "metrics")]
+    metrics::upstream_latency(stage, elapsed);
+    output
+}
+
+/// Query parameters passed on to usher.
+static PERMITTED_INCOMING_KEYS: phf::Set<&str> = phf::phf_set! {
+    "player_backend",             // mediaplayer
+    "playlist_include_framerate", // true
+    "reassignments_supported",    // true
+    "supported_codecs",           // av1,h265,h264
+    "cdm",                        // wv
+    "player_version",             // 1.36.0-rc.1
+    "fast_bread",                 // true; related to low latency mode
+    "allow_source",               // true
+    "allow_audio_only",           // true
+    "warp",                       // true; https://github.com/kixelated/warp-draft
+    "transcode_mode",             // cbr_v1
+    "platform",                   // web
+};
+// Some stuff that isn't permitted, but exists:
+//  browser_family
+//  browser_version
+//  os_name
+//  os_version
+
+async fn get_m3u8(
+    client: &Client,
+    state: &LState,
+    pd: &ProcessData,
+    token: &PlaybackAccessToken,
+) -> AppResult<Playlist> {
+    let mut url = pd.sid.get_url(&state.upstreams.usher)?;
     // set query string automatically using non-identifying parameters
     url.query_pairs_mut().extend_pairs(
         pd.query.iter().filter(|(k, _)| PERMITTED_INCOMING_KEYS.contains(k.as_ref())),
@@ -409,37 +1178,58 @@ async fn get_m3u8(client: &Client, pd: &ProcessData, token: PlaybackAccessToken)
         .append_pair("sig", &token.signature)
         .append_pair("acmb", "e30=");
     // "acmb" appears to be a tracking param, copy not permitted; value of e30= is empty object
-    let m3u = client
-        .get(url.as_str())
-        .header(USER_AGENT, pd.user_agent.as_str())
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
+    let response = client.get(url.as_str()).header(USER_AGENT, pd.user_agent.as_str()).send().await;
+    #[cfg(feature = "metrics")]
+    metrics::upstream_status("m3u8", response.as_ref().ok().map(|r| r.status()));
+    let response = response?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(usher_error(&pd.sid, status, &response.text().await.unwrap_or_default()));
+    }
+    let m3u = Playlist::parse(&response.text().await?);
 
-    const UC_START: &str = "USER-COUNTRY=\"";
-    if let Some(country) = m3u.lines().find_map(|line| line.substring_between(UC_START, "\"")) {
+    if let Some(country) = ads::user_country(&m3u) {
         info!("Twitch states that the proxy is in {}", country);
+        #[cfg(feature = "metrics")]
+        metrics::user_country(country);
     }
 
-    Ok(redact_ip(m3u))
+    Ok(m3u)
 }
 
-fn redact_ip(m3u: String) -> String {
+fn redact_ip(m3u: &mut Playlist) {
+    // if the server is behind Cloudflare or similar, the playlist exposes the real IP, which
+    // removes all the DDoS protection
     #[cfg(feature = "redact-ip")]
-    {
-        // if the server is behind Cloudflare or similar, the playlist exposes the real IP, which
-        // removes all the DDoS protection
-        let user_ip = lazy_regex::regex!(r#"USER-IP="([^"]+)""#);
-        user_ip.replace(&m3u, r#"USER-IP="1.1.1.1""#).into_owned()
-    }
+    m3u.set_user_ip("1.1.1.1");
     #[cfg(not(feature = "redact-ip"))]
-    m3u
+    let _ = m3u;
+}
+
+/// Work out why usher refused to give us a playlist.
+fn usher_error(sid: &StreamID, status: StatusCode, body: &str) -> AppError {
+    let detail: Option<UsherError> =
+        serde_json::from_str::<Vec<UsherError>>(body).ok().and_then(|v| v.into_iter().next());
+    debug!("usher returned {status}: {detail:?}");
+    match (status, sid) {
+        (StatusCode::NOT_FOUND, StreamID::Live(_)) => AppError::ChannelOffline,
+        (StatusCode::NOT_FOUND, StreamID::VOD(_)) => AppError::VodNotFound,
+        (StatusCode::FORBIDDEN, _)
+            if detail.as_ref().is_some_and(|d| d.error_code.contains("geoblock")) =>
+        {
+            AppError::GeoBlocked(detail.map(|d| d.error).unwrap_or_default())
+        }
+        (StatusCode::FORBIDDEN | StatusCode::UNAUTHORIZED, _) => AppError::TokenRejected,
+        (status, _) => AppError::Upstream { stage: "m3u8", status },
+    }
 }
 
 /// Get an access token for the given stream.
-async fn get_token(state: &LState, pd: &ProcessData) -> Result<AccessTokenResponse> {
+async fn get_token(
+    client: &Client,
+    state: &LState,
+    pd: &ProcessData,
+) -> AppResult<PlaybackAccessToken> {
     let sid = &pd.sid;
     let request = json!({
         "operationName": "PlaybackAccessToken",
@@ -459,68 +1249,45 @@ async fn get_token(state: &LState, pd: &ProcessData) -> Result<AccessTokenRespon
         },
     });
 
+    let response: AccessTokenResponse = post_gql(client, state, pd, &request).await?;
+    if !response.errors.is_empty() {
+        return Err(AppError::Gql(response.errors.into_iter().map(|e| e.message).collect()));
+    }
+    match response.data.and_then(|d| d.playback_access_token) {
+        Some(token) => Ok(token),
+        // null, for example if the VOD ID is wrong or pointing to a deleted VOD
+        None if matches!(sid, StreamID::VOD(_)) => Err(AppError::VodNotFound),
+        None => Err(AppError::ChannelNotFound),
+    }
+}
+
+/// Send a GQL request for an access token.
+pub(crate) async fn post_gql<T: DeserializeOwned>(
+    client: &Client,
+    state: &LState,
+    pd: &ProcessData,
+    request: &serde_json::Value,
+) -> AppResult<T> {
     // XXX: I've seen a different method of doing this that involves X-Device-Id (frontpage only?)
     //  2022-04-16: No longer seeing it
     //  2023-06-02: it's definitely back
 
-    Ok(state
-        .client
-        .post("https://gql.twitch.tv/gql")
+    let response = client
+        .post(state.upstreams.gql.clone())
         .header("Client-ID", state.twitch_client_id)
         .header("Device-ID", &generate_id())
         .header(USER_AGENT, pd.user_agent.as_str())
-        .json(&request)
+        .json(request)
         .send()
-        .await?
-        .error_for_status()?
-        .json()
-        .await?)
-}
-
-type AppResult<T> = std::result::Result<T, AppError>;
-
-#[derive(Debug)]
-enum AppError {
-    Anyhow(anyhow::Error),
-}
-
-// TODO: thiserror?
-
-impl From<anyhow::Error> for AppError {
-    fn from(inner: anyhow::Error) -> Self {
-        AppError::Anyhow(inner)
-    }
-}
-
-// errors are first mapped to anyhow, then to AppError
-
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response<Body> {
-        let (status, error_message) = match self {
-            AppError::Anyhow(mut e) => {
-                if let Some(e) = e.downcast_mut::<reqwest::Error>()
-                    && let Some(url) = e.url_mut()
-                {
-                    // vaporize query string since the token has the IP that twitch sees
-                    // TODO: for a fix which preserves more info, copy the query string
-                    //  except for p, play_session_id, token, sig, acmb
-                    url.set_query(None);
-                };
-                let message = format!("{e:?}");
-                let status = e
-                    .downcast_ref::<reqwest::Error>()
-                    .and_then(|e| e.status())
-                    .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
-                (status, message)
-            }
-        };
-        let body = Json(json!({
-            "code": status.as_u16(),
-            "error": error_message,
-        }));
-
-        (status, body).into_response()
+        .await;
+    #[cfg(feature = "metrics")]
+    metrics::upstream_status("token", response.as_ref().ok().map(|r| r.status()));
+    let response = response?;
+    let status = response.status();
+    if !status.is_success() {
+        return Err(AppError::Upstream { stage: "token", status });
     }
+    Ok(response.json().await?)
 }
 
 // make a pointless optimization expressible in one line at the cost of 7 lines
@@ -532,16 +1299,6 @@ impl String {
     }
 }
 
-#[ext]
-impl str {
-    fn substring_between(&self, start: &str, end: &str) -> Option<&str> {
-        let start_idx = self.find(start)?;
-        let s = &self[start_idx + start.len()..];
-        let end_idx = s.find(end)?;
-        Some(&s[..end_idx])
-    }
-}
-
 /// Generate an ID suitable for use both as a Device-ID and a play_session_id.
 /// The latter must be lowercased, as this function returns a mixed-case string.
 fn generate_id() -> String {
@@ -550,18 +1307,24 @@ fn generate_id() -> String {
 
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct AccessTokenResponse {
-    pub(crate) data: Data,
+    pub(crate) data: Option<Data>,
+    #[serde(default)]
+    pub(crate) errors: Vec<GQLError>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub(crate) struct GQLError {
+    pub(crate) message: String,
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub(crate) struct Data {
     /// The signed access token itself.
     ///
-    /// Can in fact be `null`, for example if the VOD ID is wrong or pointing to a deleted VOD.
-    /// Not modeled since we want to error out anyway. TODO: Model it so we can make a nicer error?
+    /// Can be `null`, for example if the VOD ID is wrong or pointing to a deleted VOD.
     // Name depends on whether it's a livestream or a VOD.
     #[serde(rename = "streamPlaybackAccessToken", alias = "videoPlaybackAccessToken")]
-    pub(crate) playback_access_token: PlaybackAccessToken,
+    pub(crate) playback_access_token: Option<PlaybackAccessToken>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -571,60 +1334,72 @@ pub(crate) struct PlaybackAccessToken {
 }
 
 #[allow(clippy::upper_case_acronyms)]
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub(crate) enum StreamID {
     Live(String),
     VOD(String),
+    /// A clip's slug. Unlike the others, it's case-sensitive.
+    Clip(String),
 }
 
 impl StreamID {
-    pub(crate) fn get_url(&self) -> String {
-        const BASE: &str = "https://usher.ttvnw.net/";
-        match &self {
-            Self::Live(channel) => format!("{BASE}api/channel/hls/{channel}.m3u8"),
-            Self::VOD(id) => format!("{BASE}vod/{id}.m3u8"),
-        }
+    pub(crate) fn get_url(&self, usher: &Url) -> Result<Url> {
+        Ok(match &self {
+            Self::Live(channel) => usher.join(&format!("api/channel/hls/{channel}.m3u8"))?,
+            Self::VOD(id) => usher.join(&format!("vod/{id}.m3u8"))?,
+            Self::Clip(_) => anyhow::bail!("clips aren't served by usher"),
+        })
     }
     pub(crate) fn data(&self) -> &str {
         match self {
-            Self::Live(d) | Self::VOD(d) => d.as_str(),
+            Self::Live(d) | Self::VOD(d) | Self::Clip(d) => d.as_str(),
         }
     }
 }
 
-async fn handle_error(error: BoxError) -> impl IntoResponse {
+async fn handle_error(error: BoxError) -> AppError {
     if error.is::<tower::timeout::error::Elapsed>() {
-        return (StatusCode::GATEWAY_TIMEOUT, Cow::from("timeout"));
+        #[cfg(feature = "metrics")]
+        metrics::rejection("timeout");
+        return AppError::Timeout;
     }
     if error.is::<tower::load_shed::error::Overloaded>() {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            Cow::from("service is overloaded, try again later"),
-        );
+        #[cfg(feature = "metrics")]
+        metrics::rejection("overloaded");
+        return AppError::Overloaded;
     }
 
-    (StatusCode::INTERNAL_SERVER_ERROR, Cow::from(format!("Unhandled internal error: {error}")))
+    AppError::Internal(anyhow::anyhow!("Unhandled internal error: {error}"))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{redact_ip, strExt};
+    use crate::{StreamID, common};
+    #[cfg(feature = "redact-ip")]
+    use crate::{m3u8::Playlist, redact_ip};
 
     #[test]
-    fn substring() {
-        let input = r#"se",USER-COUNTRY="RU",MANI"#;
-        assert_eq!(input.substring_between("USER-COUNTRY=\"", "\""), Some("RU"));
+    fn usher_base_override() {
+        let usher = common::parse_base_url("http://127.0.0.1:8080/mirror").unwrap();
+        let live = StreamID::Live("abc".to_owned()).get_url(&usher).unwrap();
+        assert_eq!(live.as_str(), "http://127.0.0.1:8080/mirror/api/channel/hls/abc.m3u8");
+        let vod = StreamID::VOD("123".to_owned()).get_url(&usher).unwrap();
+        assert_eq!(vod.as_str(), "http://127.0.0.1:8080/mirror/vod/123.m3u8");
     }
 
     #[cfg(feature = "redact-ip")]
     #[test]
     fn redact_ips() {
-        let input = r#"TRANSCODEMODE="cbr_v1",USER-IP="127.0.0.1",SERVING-ID="a""#.to_owned();
-        assert!(redact_ip(input).contains("USER-IP=\"1.1.1.1\""));
-        let input = r#"TRANSCODEMODE="cbr_v1",USER-IP="::1",SERVING-ID="a""#.to_owned();
-        assert!(redact_ip(input).contains("USER-IP=\"1.1.1.1\""));
-        let input =
-            r#"TRANSCODEMODE="cbr_v1",USER-IP="2001:db8::8a2e:370:7334",SERVING-ID="a""#.to_owned();
-        assert!(redact_ip(input).contains("USER-IP=\"1.1.1.1\""));
+        let redacted = |ip: &str| {
+            let info = format!(
+                "#EXT-X-TWITCH-INFO:TRANSCODEMODE=\"cbr_v1\",USER-IP=\"{ip}\",SERVING-ID=\"a\""
+            );
+            let mut m3u = Playlist::parse(&info);
+            redact_ip(&mut m3u);
+            m3u.to_string()
+        };
+        assert!(redacted("127.0.0.1").contains("USER-IP=\"1.1.1.1\""));
+        assert!(redacted("::1").contains("USER-IP=\"1.1.1.1\""));
+        assert!(redacted("2001:db8::8a2e:370:7334").contains("USER-IP=\"1.1.1.1\""));
     }
 }
@@ -0,0 +1,162 @@
+//! Prometheus metrics, served at `/metrics`.
+
+use std::time::Duration;
+
+use axum::{body::Body, extract::State};
+use http::{Response, StatusCode, header::CONTENT_TYPE};
+use once_cell::sync::Lazy;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::LState;
+
+pub(crate) const METRICS_ENDPOINT: &str = "/metrics";
+
+static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: prometheus::core::Collector + Clone + 'static>(collector: T) -> T {
+    REGISTRY.register(Box::new(collector.clone())).expect("metric registered twice");
+    collector
+}
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("luminous_requests_total", "Playlist requests received, by kind");
+    register(IntCounterVec::new(opts, &["kind"]).unwrap())
+});
+
+static UPSTREAM_LATENCY: Lazy<HistogramVec> = Lazy::new(|| {
+    let opts = HistogramOpts::new(
+        "luminous_upstream_duration_seconds",
+        "Time taken by upstream requests, by stage (token or m3u8)",
+    )
+    .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 20.0]);
+    register(HistogramVec::new(opts, &["stage"]).unwrap())
+});
+
+static UPSTREAM_STATUS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new(
+        "luminous_upstream_responses_total",
+        "Upstream responses by stage and HTTP status; \"error\" if there was no response",
+    );
+    register(IntCounterVec::new(opts, &["stage", "status"]).unwrap())
+});
+
+static REJECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new(
+        "luminous_rejections_total",
+        "Requests rejected before processing, by reason (overloaded or timeout)",
+    );
+    register(IntCounterVec::new(opts, &["reason"]).unwrap())
+});
+
+#[cfg(feature = "true-status")]
+static DEEP_STATUS: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("luminous_deep_status_total", "Deep status check outcomes");
+    register(IntCounterVec::new(opts, &["outcome"]).unwrap())
+});
+
+static USER_COUNTRY: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts =
+        Opts::new("luminous_user_country_total", "Country Twitch reported for fetched playlists");
+    register(IntCounterVec::new(opts, &["country"]).unwrap())
+});
+
+static SERVED_COUNTRY: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new("luminous_served_country_total", "Country playlists were served from");
+    register(IntCounterVec::new(opts, &["country"]).unwrap())
+});
+
+static ADS_DETECTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    let opts = Opts::new(
+        "luminous_ads_detected_total",
+        "Playlists that looked like they had ads, by the kind of sign",
+    );
+    register(IntCounterVec::new(opts, &["sign"]).unwrap())
+});
+
+static AD_SEGMENTS_REMOVED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let opts = Opts::new(
+        "luminous_ad_segments_removed",
+        "Ad segments removed from proxied media playlists, by channel",
+    );
+    register(IntGaugeVec::new(opts, &["channel"]).unwrap())
+});
+
+static RELAYED_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    let opts = Opts::new("luminous_relayed_bytes_total", "Bytes of video segments relayed");
+    register(IntCounter::with_opts(opts).unwrap())
+});
+
+static TOKEN_CACHE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    let opts = Opts::new("luminous_token_cache", "Access token cache hits, misses and size");
+    register(IntGaugeVec::new(opts, &["value"]).unwrap())
+});
+
+pub(crate) fn request(kind: &'static str) {
+    REQUESTS.with_label_values(&[kind]).inc();
+}
+
+pub(crate) fn upstream_latency(stage: &'static str, elapsed: Duration) {
+    UPSTREAM_LATENCY.with_label_values(&[stage]).observe(elapsed.as_secs_f64());
+}
+
+pub(crate) fn upstream_status(stage: &'static str, status: Option<StatusCode>) {
+    let status = status.map(|s| s.as_u16().to_string());
+    UPSTREAM_STATUS.with_label_values(&[stage, status.as_deref().unwrap_or("error")]).inc();
+}
+
+pub(crate) fn rejection(reason: &'static str) {
+    REJECTIONS.with_label_values(&[reason]).inc();
+}
+
+#[cfg(feature = "true-status")]
+pub(crate) fn deep_status(ok: bool) {
+    DEEP_STATUS.with_label_values(&[if ok { "ok" } else { "fail" }]).inc();
+}
+
+pub(crate) fn user_country(country: &str) {
+    // Twitch only ever sends two-letter codes; don't let anything else blow up label cardinality
+    if country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) {
+        USER_COUNTRY.with_label_values(&[&country.to_ascii_uppercase()]).inc();
+    }
+}
+
+pub(crate) fn served_country(country: &str) {
+    if country.len() == 2 && country.chars().all(|c| c.is_ascii_alphabetic()) {
+        SERVED_COUNTRY.with_label_values(&[&country.to_ascii_uppercase()]).inc();
+    }
+}
+
+pub(crate) fn ads_detected(sign: &'static str) {
+    ADS_DETECTED.with_label_values(&[sign]).inc();
+}
+
+pub(crate) fn relayed_bytes(bytes: usize) {
+    RELAYED_BYTES.inc_by(bytes as u64);
+}
+
+pub(crate) async fn metrics(State(state): State<LState>) -> Response<Body> {
+    let cache = state.tokens.stats();
+    TOKEN_CACHE.with_label_values(&["hits"]).set(cache.hits as i64);
+    TOKEN_CACHE.with_label_values(&["misses"]).set(cache.misses as i64);
+    TOKEN_CACHE.with_label_values(&["entries"]).set(cache.entries as i64);
+    AD_SEGMENTS_REMOVED.reset();
+    for (channel, removed) in state.stripper.removed_by_channel() {
+        AD_SEGMENTS_REMOVED.with_label_values(&[&channel]).set(removed as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    match encoder.encode(&REGISTRY.gather(), &mut buffer) {
+        Ok(()) => Response::builder()
+            .header(CONTENT_TYPE, encoder.format_type())
+            .body(Body::from(buffer))
+            .unwrap(),
+        Err(e) => Response::builder()
+            .status(500)
+            .body(Body::from(format!("encoding failed: {e}")))
+            .unwrap(),
+    }
+}
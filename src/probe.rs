@@ -0,0 +1,188 @@
+//! The `probe` subcommand: goes through Hola's countries, plays a live stream through each the
+//! way a user would, and reports where Twitch placed the proxy, how long it took, and whether
+//! there were any signs of ads. It's for picking `--country` and `--ad-free-country`, since which
+//! countries get ads changes over time.
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use serde::Serialize;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::access_log::millis;
+use crate::hello_config::Hola;
+use crate::pool::{ProxyPool, Route, RouteSpec};
+use crate::variants::VariantFilter;
+use crate::{
+    ClientSettings, LState, Opts, ProcessData, Proxies, StreamID, ads, common, get_m3u8, get_token,
+    hello, parse_country, sample,
+};
+
+#[derive(Args, Clone, Debug, PartialEq)]
+pub(crate) struct ProbeArgs {
+    /// Countries to probe, like `ru,ua,tr`. All of Hola's if not set.
+    #[arg(long, value_parser = parse_country, value_delimiter = ',')]
+    countries: Vec<String>,
+    /// Live channel to probe with. A random featured stream if not set.
+    #[arg(long)]
+    channel: Option<String>,
+    #[arg(long, value_enum, default_value = "table")]
+    format: Format,
+    /// Write the report here instead of to stdout.
+    #[arg(long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Table,
+    Json,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Report {
+    channel: String,
+    countries: Vec<Outcome>,
+    /// Countries that got a playlist without signs of ads, fastest first.
+    ad_free: Vec<String>,
+}
+
+/// How a playlist request through one country went.
+#[derive(Debug, Default, Serialize)]
+struct Outcome {
+    country: String,
+    /// Where Twitch says the proxy is, uppercase.
+    user_country: Option<String>,
+    token_ms: Option<u64>,
+    playlist_ms: Option<u64>,
+    /// The first sign of ads, if any.
+    ads: Option<String>,
+    error: Option<String>,
+}
+
+impl Outcome {
+    fn ad_free(&self) -> bool {
+        self.error.is_none() && self.ads.is_none()
+    }
+
+    fn total_ms(&self) -> u64 {
+        self.token_ms.unwrap_or_default() + self.playlist_ms.unwrap_or_default()
+    }
+}
+
+pub(crate) async fn run(opts: &Opts, args: &ProbeArgs) -> Result<()> {
+    let report = probe(opts, args).await?;
+    let text = match args.format {
+        Format::Table => report.table(),
+        Format::Json => serde_json::to_string_pretty(&report)? + "\n",
+    };
+    match &args.output {
+        Some(path) => fs::write(path, text).with_context(|| format!("writing {}", path.display())),
+        None => {
+            print!("{text}");
+            Ok(())
+        }
+    }
+}
+
+pub(crate) async fn probe(opts: &Opts, args: &ProbeArgs) -> Result<Report> {
+    let hola = Hola::setup(opts).await?;
+    let countries = if args.countries.is_empty() {
+        hello::countries(&opts.hola_url).await.context("listing Hola countries")?
+    } else {
+        args.countries.clone()
+    };
+    // the stream is looked up directly; only the playlist requests go through Hola
+    let settings = ClientSettings::from(opts);
+    let pool = Arc::new(ProxyPool::new(vec![RouteSpec::direct()], settings)?);
+    let state = LState::new(opts, Proxies { pool, hola: None })?;
+    let user_agent = common::get_user_agent(None, state.user_agent.as_ref())?;
+    let channel = match &args.channel {
+        Some(channel) => channel.to_ascii_lowercase(),
+        None => sample::find_random_stream(&state, &user_agent)
+            .await
+            .context("finding a live stream to probe with")?,
+    };
+    info!("probing {} countries with {channel}", countries.len());
+    let pd = ProcessData {
+        sid: StreamID::Live(channel.clone()),
+        query: sample::player_query(),
+        user_agent,
+        hls_endpoint: None,
+        country: None,
+        variants: VariantFilter::default(),
+    };
+    let mut outcomes = Vec::with_capacity(countries.len());
+    for country in countries {
+        let outcome = probe_country(&state, &hola, &pd, settings, country).await;
+        match &outcome.error {
+            Some(e) => warn!("{}: {e}", outcome.country),
+            None => {
+                info!("{}: in {:?}, ads: {:?}", outcome.country, outcome.user_country, outcome.ads)
+            }
+        }
+        outcomes.push(outcome);
+    }
+    let mut ad_free: Vec<_> = outcomes.iter().filter(|o| o.ad_free()).collect();
+    ad_free.sort_by_key(|o| o.total_ms());
+    let ad_free = ad_free.into_iter().map(|o| o.country.clone()).collect();
+    Ok(Report { channel, countries: outcomes, ad_free })
+}
+
+async fn probe_country(
+    state: &LState,
+    hola: &Hola,
+    pd: &ProcessData,
+    settings: ClientSettings,
+    country: String,
+) -> Outcome {
+    let mut outcome = Outcome { country, ..Outcome::default() };
+    let result: Result<()> = async {
+        let route = Route::new(hola.route_in(&outcome.country).await?, settings)?;
+        let started = Instant::now();
+        let token = get_token(&route.client, state, pd).await?;
+        outcome.token_ms = Some(millis(started.elapsed()));
+        let started = Instant::now();
+        let m3u8 = get_m3u8(&route.client, state, pd, &token).await?;
+        outcome.playlist_ms = Some(millis(started.elapsed()));
+        outcome.user_country = ads::user_country(&m3u8).map(str::to_ascii_uppercase);
+        outcome.ads = ads::detect(&token, &m3u8).map(|sign| sign.to_string());
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        outcome.error = Some(format!("{e:#}"));
+    }
+    outcome
+}
+
+impl Report {
+    fn table(&self) -> String {
+        let mut table = format!(
+            "Probed with {}\n\n{:<8} {:<8} {:>8} {:>9}  {}\n",
+            self.channel, "COUNTRY", "TWITCH", "TOKEN", "PLAYLIST", "RESULT"
+        );
+        let ms = |ms: Option<u64>| ms.map_or_else(|| "-".to_owned(), |ms| format!("{ms}ms"));
+        for outcome in &self.countries {
+            let result = match (&outcome.error, &outcome.ads) {
+                (Some(e), _) => format!("error: {e}"),
+                (None, Some(sign)) => format!("ads: {sign}"),
+                (None, None) => "ad-free".to_owned(),
+            };
+            table.push_str(&format!(
+                "{:<8} {:<8} {:>8} {:>9}  {result}\n",
+                outcome.country,
+                outcome.user_country.as_deref().unwrap_or("-"),
+                ms(outcome.token_ms),
+                ms(outcome.playlist_ms),
+            ));
+        }
+        table.push_str(&format!("\nAd-free, fastest first: {}\n", self.ad_free.join(",")));
+        table
+    }
+}
@@ -0,0 +1,248 @@
+//! Per-client rate limiting, so one client can't use up the global concurrency limit.
+//! Each client gets a token bucket; IPv6 clients are grouped by /64, since that's usually what a
+//! single customer gets. Behind a reverse proxy, the client is taken from `Forwarded` or
+//! `X-Forwarded-For`, but only if the connection comes from a trusted proxy.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http::{HeaderMap, header::FORWARDED};
+use ipnet::IpNet;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::error::AppError;
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+/// How often to forget clients whose buckets have refilled.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    clients: HashMap<IpAddr, Bucket>,
+    last_prune: Instant,
+}
+
+#[derive(Debug)]
+struct Limits {
+    /// Tokens added per second. 0 disables limiting.
+    rate: f64,
+    burst: f64,
+    trusted: Vec<IpNet>,
+}
+
+impl Limits {
+    fn new(per_minute: u32, burst: u32, trusted: Vec<IpNet>) -> Self {
+        Self { rate: f64::from(per_minute) / 60.0, burst: f64::from(burst.max(1)), trusted }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limits: RwLock<Limits>,
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    /// `per_minute` requests are allowed per client per minute, after an initial `burst`.
+    pub(crate) fn new(per_minute: u32, burst: u32, trusted: Vec<IpNet>) -> Self {
+        Self {
+            limits: RwLock::new(Limits::new(per_minute, burst, trusted)),
+            buckets: Mutex::new(Buckets { clients: HashMap::new(), last_prune: Instant::now() }),
+        }
+    }
+
+    /// Change the limits. Clients keep their current buckets.
+    #[cfg(unix)]
+    pub(crate) fn reconfigure(&self, per_minute: u32, burst: u32, trusted: Vec<IpNet>) {
+        *self.limits.write().unwrap() = Limits::new(per_minute, burst, trusted);
+    }
+
+    /// Take a token for `client`, or return how long until one is available.
+    fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let limits = self.limits.read().unwrap();
+        if limits.rate == 0.0 {
+            return Ok(());
+        }
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.last_prune) >= PRUNE_INTERVAL {
+            buckets.last_prune = now;
+            buckets.clients.retain(|_, b| limits.refill(b, now) < limits.burst);
+        }
+        let bucket = buckets
+            .clients
+            .entry(bucket_key(client))
+            .or_insert(Bucket { tokens: limits.burst, updated: now });
+        bucket.tokens = limits.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limits.rate))
+        }
+    }
+
+    /// Work out who the client really is. Forwarding headers are read from right to left, and
+    /// only as far as the hops are trusted proxies; anything further left could be forged.
+    pub(crate) fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let limits = self.limits.read().unwrap();
+        let peer = peer.to_canonical();
+        if !limits.is_trusted(peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_for(headers).into_iter().rev() {
+            let Some(hop) = hop else {
+                break; // obfuscated or garbage, so the proxy that added it is all we know
+            };
+            client = hop.to_canonical();
+            if !limits.is_trusted(client) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+/// IPv6 clients are limited by /64, everything else by address.
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip.to_canonical() {
+        IpAddr::V6(v6) => {
+            let prefix = u128::from(v6) & !(u128::MAX >> 64);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+        v4 => v4,
+    }
+}
+
+/// The client chain from `Forwarded`, falling back to `X-Forwarded-For`; leftmost is the
+/// original client. Unparseable entries are kept as `None` so they still count as a hop.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(FORWARDED.as_str());
+    if !forwarded.is_empty() {
+        return forwarded
+            .into_iter()
+            .map(|element| {
+                element
+                    .split(';')
+                    .filter_map(|pair| pair.trim().split_once('='))
+                    .find(|(key, _)| key.eq_ignore_ascii_case("for"))
+                    .and_then(|(_, node)| parse_node(node.trim_matches('"')))
+            })
+            .collect();
+    }
+    values(X_FORWARDED_FOR).into_iter().map(parse_node).collect()
+}
+
+/// Parse `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` or `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|s| s.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// Accepts CIDR ranges and bare addresses.
+pub(crate) fn parse_cidr(input: &str) -> Result<IpNet> {
+    Ok(match input.parse() {
+        Ok(net) => net,
+        Err(_) => IpNet::from(input.parse::<IpAddr>()?),
+    })
+}
+
+pub(crate) async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    req: Request,
+    next: Next,
+) -> Response {
+    let client = limiter.client_ip(peer.ip(), req.headers());
+    match limiter.check(client, Instant::now()) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            debug!("rate-limited {client}");
+            #[cfg(feature = "metrics")]
+            crate::metrics::rejection("rate_limited");
+            AppError::RateLimited { retry_after: wait.as_secs_f64().ceil() as u64 }.into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    use http::{HeaderMap, HeaderValue};
+
+    use crate::ratelimit::{RateLimiter, bucket_key, parse_cidr};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn buckets_refill_and_group_v6() {
+        let limiter = RateLimiter::new(60, 2, vec![]);
+        let now = Instant::now();
+        assert!(limiter.check(ip("2001:db8::1"), now).is_ok());
+        assert!(limiter.check(ip("2001:db8::ffff:2"), now).is_ok()); // same /64
+        assert_eq!(limiter.check(ip("2001:db8::3"), now), Err(Duration::from_secs(1)));
+        assert!(limiter.check(ip("2001:db8:0:1::1"), now).is_ok()); // different /64
+        assert!(limiter.check(ip("2001:db8::3"), now + Duration::from_secs(1)).is_ok());
+        assert_eq!(bucket_key(ip("::ffff:192.0.2.1")), ip("192.0.2.1"));
+    }
+
+    #[test]
+    fn forwarded_headers_only_from_trusted_proxies() {
+        let trusted = vec![parse_cidr("10.0.0.0/8").unwrap(), parse_cidr("::1").unwrap()];
+        let limiter = RateLimiter::new(60, 1, trusted);
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("6.6.6.6, 192.0.2.9, 10.1.1.1"));
+        // untrusted peer: headers ignored
+        assert_eq!(limiter.client_ip(ip("198.51.100.1"), &headers), ip("198.51.100.1"));
+        // trusted peer: rightmost untrusted hop, not the forgeable leftmost
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("192.0.2.9"));
+        headers.insert(
+            "forwarded",
+            HeaderValue::from_static(r#"for=192.0.2.60;proto=http, for="[2001:db8::17]:4711""#),
+        );
+        assert_eq!(limiter.client_ip(ip("::1"), &headers), ip("2001:db8::17"));
+        headers.insert("forwarded", HeaderValue::from_static("for=_hidden, for=10.2.2.2"));
+        assert_eq!(limiter.client_ip(ip("10.0.0.1"), &headers), ip("10.2.2.2"));
+    }
+}
@@ -0,0 +1,217 @@
+//! Relaying video segments through the server, for users whose networks are slow to reach
+//! Twitch's CDN. Off by default, and only available along with HLS proxy mode, since the media
+//! playlists have to be rewritten to point here. Segments are streamed through rather than
+//! buffered, with their own concurrency and bandwidth limits so they can't starve playlist
+//! requests.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::Body,
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use futures_util::StreamExt;
+use http::{HeaderMap, Response, StatusCode, header};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::error::{AppError, AppResult};
+use crate::hls::host_allowed;
+use crate::m3u8::Playlist;
+use crate::{LState, Opts};
+
+pub(crate) const SEGMENT_ENDPOINT: &str = "/hls/segment";
+const URL_PARAM: &str = "u";
+/// Request headers passed on to the CDN.
+const FORWARD_REQUEST: &[header::HeaderName] = &[
+    header::RANGE,
+    header::IF_RANGE,
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::USER_AGENT,
+];
+/// Response headers passed back to the client.
+const FORWARD_RESPONSE: &[header::HeaderName] = &[
+    header::CONTENT_TYPE,
+    header::CONTENT_LENGTH,
+    header::CONTENT_RANGE,
+    header::ACCEPT_RANGES,
+    header::ETAG,
+    header::LAST_MODIFIED,
+];
+
+#[derive(Debug)]
+pub(crate) struct Relay {
+    /// Segments aren't geo-restricted, so they're fetched directly rather than through the pool.
+    client: Client,
+    allowed_hosts: Vec<String>,
+    slots: Arc<Semaphore>,
+    bandwidth: Option<Arc<Bandwidth>>,
+}
+
+impl Relay {
+    pub(crate) fn new(opts: &Opts) -> Self {
+        let timeout = Duration::from_secs(opts.upstream_timeout);
+        let client = Client::builder()
+            .connect_timeout(timeout)
+            .read_timeout(timeout)
+            .no_gzip() // would break ranges, and video doesn't compress anyway
+            .no_brotli()
+            .no_proxy()
+            // a redirect could lead anywhere, not just to an allowed host
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("failed to build relay client"); // same as reqwest::Client::new()
+        Self {
+            client,
+            allowed_hosts: opts.relay_allowed_host.iter().map(|h| h.to_ascii_lowercase()).collect(),
+            slots: Arc::new(Semaphore::new(opts.relay_concurrency)),
+            bandwidth: (opts.relay_bandwidth > 0)
+                .then(|| Arc::new(Bandwidth::new(opts.relay_bandwidth * 1024))),
+        }
+    }
+
+    /// Point a media playlist's segments, where allowed, at `endpoint`.
+    pub(crate) fn rewrite_segments(&self, m3u8: &mut Playlist, endpoint: &Url) {
+        m3u8.map_uris(|uri| {
+            let url = Url::parse(uri).ok()?;
+            if !host_allowed(&self.allowed_hosts, &url) {
+                return None;
+            }
+            let mut relayed = endpoint.clone();
+            relayed.query_pairs_mut().append_pair(URL_PARAM, uri);
+            Some(relayed.into())
+        })
+    }
+}
+
+/// A token bucket shared by all relayed segments, refilling at `rate` bytes per second. Chunks
+/// are sent as soon as they arrive and the debt is paid off by waiting before the next one.
+#[derive(Debug)]
+struct Bandwidth {
+    rate: f64,
+    /// Bytes available, which goes negative while in debt, and when it was last updated.
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl Bandwidth {
+    fn new(bytes_per_second: u64) -> Self {
+        let rate = bytes_per_second as f64;
+        Self { rate, bucket: Mutex::new((rate, Instant::now())) }
+    }
+
+    /// Take `bytes`, returning how long to wait to stay under the rate.
+    fn take(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let (tokens, updated) = *bucket;
+        // allows a second's worth of burst
+        let tokens = (tokens + now.duration_since(updated).as_secs_f64() * self.rate)
+            .min(self.rate)
+            - bytes as f64;
+        *bucket = (tokens, now);
+        if tokens < 0.0 { Duration::from_secs_f64(-tokens / self.rate) } else { Duration::ZERO }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SegmentQuery {
+    u: String,
+}
+
+pub(crate) async fn segment(
+    Query(query): Query<SegmentQuery>,
+    headers: HeaderMap,
+    State(state): State<LState>,
+) -> Response<Body> {
+    #[cfg(feature = "metrics")]
+    crate::metrics::request("segment");
+    relay(query, &headers, &state).await.into_response()
+}
+
+async fn relay(
+    query: SegmentQuery,
+    headers: &HeaderMap,
+    state: &LState,
+) -> AppResult<Response<Body>> {
+    let relay = state.relay.expect("route only exists with segment relaying on");
+    let url = Url::parse(&query.u)
+        .map_err(|e| AppError::BadRequest(format!("invalid segment URL: {e}")))?;
+    if !host_allowed(&relay.allowed_hosts, &url) {
+        return Err(AppError::BadRequest("not a Twitch CDN URL".to_owned()));
+    }
+    let permit = relay.slots.clone().try_acquire_owned().map_err(|_| {
+        #[cfg(feature = "metrics")]
+        crate::metrics::rejection("relay_overloaded");
+        AppError::Overloaded
+    })?;
+
+    let mut rb = relay.client.get(url.as_str());
+    for name in FORWARD_REQUEST {
+        for value in headers.get_all(name) {
+            rb = rb.header(name, value);
+        }
+    }
+    let upstream = rb.send().await;
+    #[cfg(feature = "metrics")]
+    crate::metrics::upstream_status("segment", upstream.as_ref().ok().map(|r| r.status()));
+    let upstream = upstream?;
+    let status = upstream.status();
+    let passed_on = [StatusCode::NOT_MODIFIED, StatusCode::RANGE_NOT_SATISFIABLE];
+    if !(status.is_success() || passed_on.contains(&status)) {
+        return Err(AppError::Upstream { stage: "segment", status });
+    }
+
+    let mut response = Response::builder().status(status);
+    for name in FORWARD_RESPONSE {
+        for value in upstream.headers().get_all(name) {
+            response = response.header(name, value);
+        }
+    }
+    let bandwidth = relay.bandwidth.clone();
+    // nothing is read ahead of what the client has taken, so buffering is bounded by the
+    // connection buffers; the slot is held until the body is done with
+    let body = upstream.bytes_stream().then(move |chunk| {
+        let _slot = &permit;
+        let bandwidth = bandwidth.clone();
+        async move {
+            if let Ok(bytes) = &chunk {
+                #[cfg(feature = "metrics")]
+                crate::metrics::relayed_bytes(bytes.len());
+                if let Some(bandwidth) = bandwidth {
+                    let wait = bandwidth.take(bytes.len(), Instant::now());
+                    if !wait.is_zero() {
+                        tokio::time::sleep(wait).await;
+                    }
+                }
+            }
+            chunk
+        }
+    });
+    Ok(response.body(Body::from_stream(body)).expect("headers came from a valid response"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::relay::Bandwidth;
+
+    #[test]
+    fn bandwidth_debt() {
+        let bandwidth = Bandwidth::new(1000);
+        let now = Instant::now();
+        assert_eq!(bandwidth.take(1000, now), Duration::ZERO); // a second's burst
+        assert_eq!(bandwidth.take(500, now), Duration::from_millis(500));
+        assert_eq!(bandwidth.take(500, now), Duration::from_secs(1));
+        // it's paid off by waiting, and idle time doesn't build up more than the burst
+        assert_eq!(bandwidth.take(0, now + Duration::from_secs(1)), Duration::ZERO);
+        assert_eq!(bandwidth.take(1000, now + Duration::from_secs(60)), Duration::ZERO);
+        assert_eq!(bandwidth.take(1, now + Duration::from_secs(60)), Duration::from_millis(1));
+    }
+}
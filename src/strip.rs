@@ -0,0 +1,308 @@
+//! Removing stitched ad segments from live media playlists served in HLS proxy mode.
+//!
+//! Dropping segments means renumbering the rest, and the numbering has to agree between one
+//! refresh of a playlist and the next or the player stalls. So each playlist gets a [`Timeline`]
+//! remembering which segments were ads, and numbers are derived from that rather than from
+//! whatever happens to be in the current window.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::m3u8::{Attributes, Inf, Item, Line, Playlist};
+
+/// Timelines kept before they're all thrown away; not worth being clever about.
+const MAX_TIMELINES: usize = 1024;
+/// Channels counted individually; the rest are lumped together.
+const MAX_CHANNELS: usize = 1024;
+const OTHER_CHANNELS: &str = "other";
+
+/// Tags that only describe the segment after them, so go when it does. `EXTINF` and
+/// `EXT-X-DISCONTINUITY` are too.
+const SEGMENT_TAGS: &[&str] =
+    &["#EXT-X-PROGRAM-DATE-TIME", "#EXT-X-BYTERANGE", "#EXT-X-GAP", "#EXT-X-BITRATE"];
+/// Found in the `ID` or `CLASS` of `EXT-X-DATERANGE` tags announcing or tracking an ad break.
+const AD_DATERANGE_MARKERS: &[&str] = &["stitched-ad", "twitch-ad", "twitch-maf-ad"];
+/// Twitch's own attributes on ad break `EXT-X-DATERANGE` tags start with this.
+const AD_ATTRIBUTE_PREFIX: &str = "X-TV-TWITCH-AD";
+/// Found in the titles of ad segments. Live content is titled "live".
+const AD_TITLE_MARKERS: &[&str] = &["Amazon", "stitched"];
+const LIVE_TITLE: &str = "live";
+
+/// What's been done to one media playlist so far, by original media sequence number.
+#[derive(Debug, Default)]
+pub(crate) struct Timeline {
+    /// Segments that were ads.
+    ads: BTreeSet<u64>,
+    /// Ad segments that carried a discontinuity, which went with them.
+    dropped: BTreeSet<u64>,
+    /// Kept segments given a discontinuity where an ad break was cut out.
+    added: BTreeSet<u64>,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct AdStripper {
+    timelines: Mutex<HashMap<String, Timeline>>,
+    by_channel: Mutex<HashMap<String, u64>>,
+}
+
+impl AdStripper {
+    /// Strip ads from a live media playlist fetched from `source`.
+    pub(crate) fn strip(&self, channel: &str, source: &Url, playlist: Playlist) -> Playlist {
+        let mut key = source.clone();
+        key.set_query(None); // the same playlist can be fetched with different tokens
+        let mut timelines = self.timelines.lock().unwrap();
+        if timelines.len() >= MAX_TIMELINES && !timelines.contains_key(key.as_str()) {
+            timelines.clear();
+        }
+        let (stripped, removed) = strip(playlist, timelines.entry(key.into()).or_default());
+        drop(timelines);
+        if removed > 0 {
+            debug!("removed {removed} ad segments from {channel}'s stream");
+            let mut by_channel = self.by_channel.lock().unwrap();
+            let channel = if by_channel.len() < MAX_CHANNELS || by_channel.contains_key(channel) {
+                channel
+            } else {
+                OTHER_CHANNELS
+            };
+            *by_channel.entry(channel.to_owned()).or_default() += removed;
+        }
+        stripped
+    }
+
+    /// Ad segments removed so far, per channel.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn removed_by_channel(&self) -> HashMap<String, u64> {
+        self.by_channel.lock().unwrap().clone()
+    }
+}
+
+fn is_segment_tag(item: &Item) -> bool {
+    match item {
+        Item::Inf(_) | Item::Discontinuity => true,
+        Item::Tag { name, .. } => SEGMENT_TAGS.contains(&name.as_str()),
+        Item::Other(line) => {
+            SEGMENT_TAGS.contains(&line.split_once(':').map_or(line.as_str(), |(name, _)| name))
+        }
+        _ => false,
+    }
+}
+
+/// Whether an `EXT-X-DATERANGE` tag is about an ad break.
+pub(crate) fn is_ad_break(daterange: &Attributes) -> bool {
+    ["ID", "CLASS"]
+        .into_iter()
+        .filter_map(|name| daterange.get(name))
+        .any(|value| AD_DATERANGE_MARKERS.iter().any(|marker| value.contains(marker)))
+        || daterange.names().any(|name| name.starts_with(AD_ATTRIBUTE_PREFIX))
+}
+
+fn is_ad_title(title: &str) -> bool {
+    AD_TITLE_MARKERS.iter().any(|marker| title.contains(marker))
+}
+
+/// Remove ad segments, returning the new playlist and how many ad segments hadn't been seen
+/// before. Playlists without ads come back unchanged.
+fn strip(playlist: Playlist, timeline: &mut Timeline) -> (Playlist, u64) {
+    let eol = playlist.ending();
+    let first = playlist
+        .lines
+        .iter()
+        .find_map(|line| match line.item {
+            Item::MediaSequence(n) => Some(n),
+            _ => None,
+        })
+        .unwrap_or(0);
+    let mut out = Vec::with_capacity(playlist.lines.len());
+    let mut segment: Vec<Line> = vec![]; // lines of the segment being read
+    let mut seq = first;
+    let mut in_ad_break = false;
+    let mut cut = false; // an ad break was just cut out
+    let mut last_was_ad = false;
+    let mut new_ads = 0;
+
+    for mut line in playlist.lines {
+        match &mut line.item {
+            Item::MediaSequence(n) => {
+                *n -= timeline.ads.range(..*n).count() as u64;
+                out.push(line);
+            }
+            Item::DiscontinuitySequence(n) => {
+                let dropped = timeline.dropped.range(..first).count() as u64;
+                let added = timeline.added.range(..first).count() as u64;
+                *n = (*n + added).saturating_sub(dropped);
+                out.push(line);
+            }
+            Item::DateRange(daterange) if is_ad_break(daterange) => in_ad_break = true,
+            // prefetched segments continue on from the last one
+            Item::Prefetch(_) => {
+                if !last_was_ad {
+                    out.push(line);
+                }
+            }
+            Item::Uri(_) => {
+                let title = segment
+                    .iter()
+                    .find_map(|l| match &l.item {
+                        Item::Inf(Inf { title, .. }) => title.as_deref(),
+                        _ => None,
+                    })
+                    .unwrap_or("");
+                if title == LIVE_TITLE {
+                    in_ad_break = false;
+                }
+                let is_ad = timeline.ads.contains(&seq)
+                    || is_ad_title(title)
+                    || (in_ad_break && title != LIVE_TITLE);
+                let has_discontinuity = segment.iter().any(|l| l.item == Item::Discontinuity);
+                if is_ad {
+                    if timeline.ads.insert(seq) {
+                        new_ads += 1;
+                    }
+                    if has_discontinuity {
+                        timeline.dropped.insert(seq);
+                    }
+                    cut = true;
+                    // keep tags like EXT-X-KEY that carry on to later segments
+                    out.extend(segment.drain(..).filter(|l| !is_segment_tag(&l.item)));
+                } else {
+                    if !has_discontinuity && (cut || timeline.added.contains(&seq)) {
+                        timeline.added.insert(seq);
+                        out.push(Line { item: Item::Discontinuity, ending: eol });
+                    }
+                    cut = false;
+                    out.append(&mut segment);
+                    out.push(line);
+                }
+                last_was_ad = is_ad;
+                seq += 1;
+            }
+            item if segment.is_empty() && !is_segment_tag(item) => out.push(line),
+            _ => segment.push(line),
+        }
+    }
+    out.append(&mut segment);
+    (Playlist { lines: out }, new_ads)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::m3u8::Playlist;
+    use crate::strip::Timeline;
+
+    fn strip(m3u8: &str, timeline: &mut Timeline) -> (String, u64) {
+        let (stripped, removed) = crate::strip::strip(Playlist::parse(m3u8), timeline);
+        (stripped.to_string(), removed)
+    }
+
+    const AD_BREAK: &str = "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:100
+#EXT-X-DISCONTINUITY-SEQUENCE:4
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000Z
+#EXTINF:2.000,live
+https://edge.example/100.ts
+#EXT-X-DATERANGE:ID=\"stitched-ad-1\",CLASS=\"twitch-stitched-ad\",DURATION=4.000
+#EXT-X-DISCONTINUITY
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:02.000Z
+#EXTINF:2.000,Amazon|123
+https://ads.example/101.ts
+#EXTINF:2.000,Amazon|123
+https://ads.example/102.ts
+#EXT-X-DISCONTINUITY
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:06.000Z
+#EXTINF:2.000,live
+https://edge.example/103.ts
+#EXT-X-TWITCH-PREFETCH:https://edge.example/104.ts
+";
+
+    #[test]
+    fn removes_ad_break() {
+        let mut timeline = Timeline::default();
+        let (stripped, removed) = strip(AD_BREAK, &mut timeline);
+        assert_eq!(removed, 2);
+        assert_eq!(
+            stripped,
+            "#EXTM3U
+#EXT-X-VERSION:3
+#EXT-X-TARGETDURATION:6
+#EXT-X-MEDIA-SEQUENCE:100
+#EXT-X-DISCONTINUITY-SEQUENCE:4
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:00.000Z
+#EXTINF:2.000,live
+https://edge.example/100.ts
+#EXT-X-DISCONTINUITY
+#EXT-X-PROGRAM-DATE-TIME:2024-01-01T00:00:06.000Z
+#EXTINF:2.000,live
+https://edge.example/103.ts
+#EXT-X-TWITCH-PREFETCH:https://edge.example/104.ts
+"
+        );
+        // seen again, nothing new to count
+        assert_eq!(strip(AD_BREAK, &mut timeline), (stripped, 0));
+    }
+
+    #[test]
+    fn numbering_is_stable_as_the_window_moves() {
+        let mut timeline = Timeline::default();
+        strip(AD_BREAK, &mut timeline);
+        // the window has moved on to start at the first live segment after the break
+        let later = "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:103
+#EXT-X-DISCONTINUITY-SEQUENCE:5
+#EXTINF:2.000,live
+https://edge.example/103.ts
+#EXTINF:2.000,live
+https://edge.example/104.ts
+";
+        let (stripped, removed) = strip(later, &mut timeline);
+        assert_eq!(removed, 0);
+        // 103 was the player's 101, and the player has seen 5 discontinuities before it; one
+        // came from the first ad segment and was dropped, the one on 103 itself is still there
+        assert_eq!(
+            stripped,
+            "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:101
+#EXT-X-DISCONTINUITY-SEQUENCE:4
+#EXTINF:2.000,live
+https://edge.example/103.ts
+#EXTINF:2.000,live
+https://edge.example/104.ts
+"
+        );
+    }
+
+    #[test]
+    fn whole_window_of_ads() {
+        let mut timeline = Timeline::default();
+        let playlist = "#EXTM3U\r\n#EXT-X-MEDIA-SEQUENCE:7\r\n\
+            #EXTINF:2.000,Amazon\r\nhttps://ads.example/7.ts\r\n\
+            #EXTINF:2.000,Amazon\r\nhttps://ads.example/8.ts\r\n\
+            #EXT-X-TWITCH-PREFETCH:https://ads.example/9.ts\r\n";
+        let (stripped, removed) = strip(playlist, &mut timeline);
+        assert_eq!(removed, 2);
+        assert_eq!(stripped, "#EXTM3U\r\n#EXT-X-MEDIA-SEQUENCE:7\r\n");
+        // the first live segment after the break gets the number the player expects next,
+        // and a discontinuity since it doesn't follow on from what the player last had
+        let after = "#EXTM3U\r\n#EXT-X-MEDIA-SEQUENCE:8\r\n\
+            #EXTINF:2.000,Amazon\r\nhttps://ads.example/8.ts\r\n\
+            #EXTINF:2.000,live\r\nhttps://edge.example/9.ts\r\n";
+        let (stripped, removed) = strip(after, &mut timeline);
+        assert_eq!(removed, 0);
+        assert_eq!(
+            stripped,
+            "#EXTM3U\r\n#EXT-X-MEDIA-SEQUENCE:7\r\n\
+            #EXT-X-DISCONTINUITY\r\n#EXTINF:2.000,live\r\nhttps://edge.example/9.ts\r\n"
+        );
+    }
+
+    #[test]
+    fn untouched_without_ads() {
+        let playlist = "#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:1\n#EXTINF:2.000,live\nhttps://e/1.ts\n";
+        assert_eq!(strip(playlist, &mut Timeline::default()), (playlist.to_owned(), 0));
+    }
+}
@@ -0,0 +1,164 @@
+//! Cache of playback access tokens, so repeat requests for a stream can skip the GQL round trip.
+//! Tokens are reused until shortly before the expiry Twitch embeds in them. A token is bound to
+//! the IP (and so the country) that fetched it, so each route keeps its own.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::{PlaybackAccessToken, StreamID};
+
+/// Stop handing out a token this long before it expires, so it can't expire in flight.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(60);
+/// Upper bound on cached tokens; expired ones are pruned first when it's reached.
+const MAX_ENTRIES: usize = 4096;
+
+/// The parts of the token's `value` JSON we care about.
+#[derive(Deserialize)]
+struct TokenValue {
+    /// Unix timestamp, in seconds.
+    expires: Option<u64>,
+}
+
+/// A stream, and the label of the route its token was fetched through.
+type Key = (StreamID, String);
+
+#[derive(Debug)]
+struct Entry {
+    token: PlaybackAccessToken,
+    expires: SystemTime,
+}
+
+#[derive(Copy, Clone, Debug, Serialize)]
+pub(crate) struct CacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    pub(crate) entries: usize,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TokenCache {
+    entries: Mutex<HashMap<Key, Entry>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl TokenCache {
+    /// A token for `sid` fetched through `via` that is still good for at least
+    /// [`EXPIRY_MARGIN`], if we have one.
+    pub(crate) fn get(&self, sid: &StreamID, via: &str) -> Option<PlaybackAccessToken> {
+        let entries = self.entries.lock().unwrap();
+        let token = entries
+            .get(&(sid.clone(), via.to_owned()))
+            .filter(|e| e.expires > SystemTime::now() + EXPIRY_MARGIN)
+            .map(|e| e.token.clone());
+        let counter = if token.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        token
+    }
+
+    /// Cache `token`, unless we can't tell when it expires.
+    pub(crate) fn insert(&self, sid: StreamID, via: &str, token: &PlaybackAccessToken) {
+        let Some(expires) = expiry(token) else {
+            debug!("not caching token for {sid:?}, no expiry found");
+            return;
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            let now = SystemTime::now();
+            entries.retain(|_, e| e.expires > now);
+            if entries.len() >= MAX_ENTRIES {
+                entries.clear(); // everything is live; not worth being clever about
+            }
+        }
+        entries.insert((sid, via.to_owned()), Entry { token: token.clone(), expires });
+    }
+
+    /// Forget the token for `sid` fetched through `via`, after usher refused it.
+    pub(crate) fn evict(&self, sid: &StreamID, via: &str) {
+        self.entries.lock().unwrap().remove(&(sid.clone(), via.to_owned()));
+    }
+
+    /// Forget every token, after the routes they were fetched through have changed.
+    pub(crate) fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: self.entries.lock().unwrap().len(),
+        }
+    }
+}
+
+fn expiry(token: &PlaybackAccessToken) -> Option<SystemTime> {
+    let value: TokenValue = serde_json::from_str(&token.value).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(value.expires?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use crate::tokens::TokenCache;
+    use crate::{PlaybackAccessToken, StreamID};
+
+    fn token(expires: u64) -> PlaybackAccessToken {
+        PlaybackAccessToken {
+            value: format!(
+                r#"{{"adblock":false,"channel":"abc","expires":{expires},"show_ads":false}}"#
+            ),
+            signature: "0123456789abcdef".to_owned(),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    #[test]
+    fn reuses_until_near_expiry() {
+        let cache = TokenCache::default();
+        let live = StreamID::Live("abc".to_owned());
+        assert!(cache.get(&live, "direct").is_none());
+        cache.insert(live.clone(), "direct", &token(now() + 20 * 60));
+        assert!(cache.get(&live, "direct").is_some());
+        cache.insert(live.clone(), "direct", &token(now() + 30)); // inside the safety margin
+        assert!(cache.get(&live, "direct").is_none());
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (1, 2, 1));
+    }
+
+    #[test]
+    fn kept_per_route() {
+        let cache = TokenCache::default();
+        let live = StreamID::Live("abc".to_owned());
+        cache.insert(live.clone(), "http://10.0.0.1:8080", &token(now() + 20 * 60));
+        assert!(cache.get(&live, "http://10.0.0.1:8080").is_some());
+        assert!(cache.get(&live, "http://10.0.0.2:8080").is_none());
+        cache.evict(&live, "http://10.0.0.2:8080");
+        assert_eq!(cache.stats().entries, 1);
+        cache.clear();
+        assert!(cache.get(&live, "http://10.0.0.1:8080").is_none());
+    }
+
+    #[test]
+    fn evicts_and_skips_unparseable() {
+        let cache = TokenCache::default();
+        let vod = StreamID::VOD("123".to_owned());
+        cache.insert(vod.clone(), "direct", &token(now() + 20 * 60));
+        cache.evict(&vod, "direct");
+        assert!(cache.get(&vod, "direct").is_none());
+        let mut bad = token(0);
+        bad.value = "not json".to_owned();
+        cache.insert(vod.clone(), "direct", &bad);
+        assert_eq!(cache.stats().entries, 0);
+    }
+}
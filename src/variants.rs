@@ -0,0 +1,384 @@
+//! Trimming and reordering the variants of a master playlist, for viewers whose connections can't
+//! keep up with what Twitch offers. Limits come from the server's options, and each request can
+//! tighten them with query parameters.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::Result;
+#[allow(unused)]
+use tracing::{debug, error, info, warn};
+
+use crate::Opts;
+use crate::error::{AppError, AppResult};
+use crate::m3u8::{Item, Line, Playlist, StreamInf};
+
+/// Video codecs Twitch may offer, named as in the `supported_codecs` usher parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    Av1,
+    H265,
+    H264,
+}
+
+impl Codec {
+    /// The codec an entry of a `CODECS` attribute is, if it's a video one.
+    fn of(entry: &str) -> Option<Self> {
+        match entry.split('.').next()? {
+            "av01" => Some(Self::Av1),
+            "hev1" | "hvc1" => Some(Self::H265),
+            "avc1" | "avc3" => Some(Self::H264),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "av1" => Ok(Self::Av1),
+            "h265" | "hevc" => Ok(Self::H265),
+            "h264" | "avc" => Ok(Self::H264),
+            _ => anyhow::bail!("unknown codec {s}, expected av1, h265 or h264"),
+        }
+    }
+}
+
+/// What to keep of a master playlist, and in what order.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct VariantFilter {
+    max_height: Option<u32>,
+    /// In bits per second, like `BANDWIDTH`.
+    max_bandwidth: Option<u64>,
+    /// Empty allows any.
+    codecs: Vec<Codec>,
+    audio_only: bool,
+    /// Heights to put first, in this order; the player usually starts with the first variant.
+    prefer: Vec<u32>,
+}
+
+impl VariantFilter {
+    pub(crate) fn new(opts: &Opts) -> Self {
+        Self {
+            max_height: opts.max_resolution,
+            max_bandwidth: opts.max_bitrate.map(|kbps| kbps * 1000),
+            codecs: opts.codec.clone(),
+            audio_only: false,
+            prefer: opts.prefer_resolution.clone(),
+        }
+    }
+
+    /// The server's filter, tightened by a request's `max_resolution`, `max_bitrate`, `codecs`
+    /// and `audio_only`, with `prefer` replacing the preferred order.
+    pub(crate) fn with_query(&self, query: &HashMap<String, String>) -> AppResult<Self> {
+        fn param<T: FromStr>(query: &HashMap<String, String>, key: &str) -> AppResult<Option<T>> {
+            query
+                .get(key)
+                .map(|v| v.trim_end_matches('p').parse())
+                .transpose()
+                .map_err(|_| AppError::BadRequest(format!("invalid {key}")))
+        }
+        fn list<T: FromStr>(query: &HashMap<String, String>, key: &str) -> AppResult<Vec<T>> {
+            let Some(value) = query.get(key) else {
+                return Ok(Vec::new());
+            };
+            value
+                .split(',')
+                .map(|v| v.trim_end_matches('p').parse())
+                .collect::<Result<_, _>>()
+                .map_err(|_| AppError::BadRequest(format!("invalid {key}")))
+        }
+        let mut filter = self.clone();
+        if let Some(height) = param(query, "max_resolution")? {
+            filter.max_height = Some(self.max_height.map_or(height, |max: u32| max.min(height)));
+        }
+        if let Some(kbps) = param::<u64>(query, "max_bitrate")? {
+            let bandwidth = kbps
+                .checked_mul(1000)
+                .ok_or_else(|| AppError::BadRequest("invalid max_bitrate".to_owned()))?;
+            filter.max_bandwidth = Some(self.max_bandwidth.map_or(bandwidth, |m| m.min(bandwidth)));
+        }
+        let codecs: Vec<Codec> = list(query, "codecs")?;
+        if !codecs.is_empty() {
+            filter.codecs = if self.codecs.is_empty() {
+                codecs
+            } else {
+                // keep the server's restriction even if the request names nothing it allows,
+                // which ends up falling back to the smallest variant
+                let allowed: Vec<_> =
+                    codecs.into_iter().filter(|c| self.codecs.contains(c)).collect();
+                if allowed.is_empty() { self.codecs.clone() } else { allowed }
+            };
+        }
+        filter.audio_only = query.get("audio_only").is_some_and(|v| v == "true" || v == "1");
+        let prefer = list(query, "prefer")?;
+        if !prefer.is_empty() {
+            filter.prefer = prefer;
+        }
+        Ok(filter)
+    }
+
+    fn is_noop(&self) -> bool {
+        self.max_height.is_none()
+            && self.max_bandwidth.is_none()
+            && self.codecs.is_empty()
+            && !self.audio_only
+            && self.prefer.is_empty()
+    }
+
+    fn allows(&self, variant: &Variant) -> bool {
+        if self.audio_only {
+            return variant.is_audio();
+        }
+        self.max_height.is_none_or(|max| variant.height.is_none_or(|h| h <= max))
+            && self.max_bandwidth.is_none_or(|max| variant.bandwidth <= max)
+            && (self.codecs.is_empty() || variant.codec.is_none_or(|c| self.codecs.contains(&c)))
+    }
+
+    /// Filter and reorder a master playlist's variants. If nothing is left, the one with the
+    /// lowest bandwidth is kept, since an empty playlist can't be played at all.
+    pub(crate) fn apply(&self, playlist: &mut Playlist) {
+        if self.is_noop() {
+            return;
+        }
+        let master = Master::split(std::mem::take(&mut playlist.lines));
+        let mut kept: Vec<&Variant> = master.variants.iter().filter(|v| self.allows(v)).collect();
+        if kept.is_empty() {
+            debug!("no variants left after filtering, keeping the smallest");
+            kept.extend(master.variants.iter().min_by_key(|v| v.bandwidth));
+        }
+        let rank = |v: &Variant| {
+            v.height.and_then(|h| self.prefer.iter().position(|&p| p == h)).unwrap_or(usize::MAX)
+        };
+        kept.sort_by_key(|v| rank(v)); // stable, so otherwise Twitch's order is kept
+        playlist.lines = master.join(&kept);
+    }
+}
+
+/// URI of the variant with the highest bandwidth, for players that are handed a single stream.
+pub(crate) fn best(playlist: &Playlist) -> Option<&str> {
+    let mut bandwidth = None;
+    let mut best: Option<(u64, &str)> = None;
+    for line in &playlist.lines {
+        match &line.item {
+            Item::StreamInf(inf) => bandwidth = Some(inf.bandwidth().unwrap_or(0)),
+            Item::Uri(uri) => {
+                if let Some(b) = bandwidth.take()
+                    && best.is_none_or(|(max, _)| b > max)
+                {
+                    best = Some((b, uri));
+                }
+            }
+            _ => {}
+        }
+    }
+    best.map(|(_, uri)| uri)
+}
+
+/// A master playlist split into its variants.
+struct Master {
+    /// Everything before the first variant.
+    head: Vec<Line>,
+    /// `EXT-X-MEDIA` lines and the group they're in.
+    media: Vec<(String, Line)>,
+    variants: Vec<Variant>,
+    /// Anything after the last variant's URI.
+    tail: Vec<Line>,
+}
+
+/// An `EXT-X-STREAM-INF` tag, any other tags before it, and its URI.
+struct Variant {
+    lines: Vec<Line>,
+    bandwidth: u64,
+    height: Option<u32>,
+    /// The video codec, if there is one.
+    codec: Option<Codec>,
+    /// Media groups it uses.
+    groups: Vec<String>,
+}
+
+impl Variant {
+    fn new(inf: &StreamInf, lines: Vec<Line>) -> Self {
+        Self {
+            bandwidth: inf.bandwidth().unwrap_or(0),
+            height: inf.resolution().map(|(_, height)| height),
+            codec: inf.codecs().find_map(Codec::of),
+            groups: inf.groups().map(str::to_owned).collect(),
+            lines,
+        }
+    }
+
+    fn is_audio(&self) -> bool {
+        self.height.is_none() && self.codec.is_none()
+    }
+}
+
+impl Master {
+    fn split(lines: Vec<Line>) -> Self {
+        let mut master =
+            Master { head: Vec::new(), media: Vec::new(), variants: Vec::new(), tail: Vec::new() };
+        // lines since the last variant, which belong to the next one if there is one
+        let mut pending: Vec<Line> = Vec::new();
+        let mut inf: Option<StreamInf> = None;
+        for line in lines {
+            match &line.item {
+                Item::Media(media) => {
+                    master.media.push((media.group_id().unwrap_or_default().to_owned(), line));
+                }
+                Item::StreamInf(stream_inf) => {
+                    inf = Some(stream_inf.clone());
+                    pending.push(line);
+                }
+                Item::Uri(_) if inf.is_some() => {
+                    pending.push(line);
+                    let inf = inf.take().expect("just checked");
+                    master.variants.push(Variant::new(&inf, std::mem::take(&mut pending)));
+                }
+                _ if master.variants.is_empty() && inf.is_none() => master.head.push(line),
+                _ => pending.push(line),
+            }
+        }
+        master.tail = pending;
+        master
+    }
+
+    fn join(&self, variants: &[&Variant]) -> Vec<Line> {
+        let mut out = self.head.clone();
+        // media no variant uses at all stay up top rather than disappearing
+        let mut written: Vec<_> = self
+            .media
+            .iter()
+            .map(|(group, line)| {
+                let unused = !self.variants.iter().any(|v| v.groups.contains(group));
+                if unused {
+                    out.push(line.clone());
+                }
+                unused
+            })
+            .collect();
+        for variant in variants {
+            // media go right before the first variant that uses them, as Twitch puts them
+            for (i, (group, line)) in self.media.iter().enumerate() {
+                if !written[i] && variant.groups.contains(group) {
+                    written[i] = true;
+                    out.push(line.clone());
+                }
+            }
+            out.extend(variant.lines.iter().cloned());
+        }
+        out.extend(self.tail.iter().cloned());
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::m3u8::Playlist;
+    use crate::variants::{Codec, VariantFilter, best};
+
+    const MASTER: &str = "#EXTM3U\n\
+        #EXT-X-TWITCH-INFO:NODE=\"video-edge\",USER-COUNTRY=\"RU\"\n\
+        #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"chunked\",NAME=\"1080p60 (source)\",AUTOSELECT=YES,DEFAULT=YES\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=8000000,RESOLUTION=1920x1080,CODECS=\"av01.0.08M.08,mp4a.40.2\",VIDEO=\"chunked\",FRAME-RATE=60.000\n\
+        https://cdn.example/chunked.m3u8\n\
+        #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"720p60\",NAME=\"720p60\",AUTOSELECT=YES,DEFAULT=YES\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=3000000,RESOLUTION=1280x720,CODECS=\"avc1.4D401F,mp4a.40.2\",VIDEO=\"720p60\",FRAME-RATE=60.000\n\
+        https://cdn.example/720p60.m3u8\n\
+        #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"480p30\",NAME=\"480p\",AUTOSELECT=YES,DEFAULT=YES\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=1400000,RESOLUTION=852x480,CODECS=\"avc1.4D401F,mp4a.40.2\",VIDEO=\"480p30\",FRAME-RATE=30.000\n\
+        https://cdn.example/480p30.m3u8\n\
+        #EXT-X-MEDIA:TYPE=VIDEO,GROUP-ID=\"audio_only\",NAME=\"audio_only\",AUTOSELECT=NO,DEFAULT=NO\n\
+        #EXT-X-STREAM-INF:BANDWIDTH=160000,CODECS=\"mp4a.40.2\",VIDEO=\"audio_only\"\n\
+        https://cdn.example/audio_only.m3u8\n";
+
+    fn uris(m3u8: &str) -> Vec<&str> {
+        m3u8.lines().filter(|l| !l.starts_with('#')).map(|l| &l[20..l.len() - 5]).collect()
+    }
+
+    fn run(filter: &VariantFilter, m3u8: &str) -> String {
+        let mut playlist = Playlist::parse(m3u8);
+        filter.apply(&mut playlist);
+        playlist.to_string()
+    }
+
+    fn query(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn untouched_without_limits() {
+        let filter = VariantFilter::default().with_query(&HashMap::new()).unwrap();
+        assert_eq!(run(&filter, MASTER), MASTER);
+        // parsing and writing everything back doesn't change anything either
+        let filter = VariantFilter { prefer: vec![1], ..Default::default() };
+        assert_eq!(run(&filter, MASTER), MASTER);
+        let crlf = MASTER.replace('\n', "\r\n");
+        assert_eq!(run(&filter, &crlf), crlf);
+    }
+
+    #[test]
+    fn limits() {
+        let base = VariantFilter::default();
+        let apply = |pairs| run(&base.with_query(&query(pairs)).unwrap(), MASTER);
+        let filtered = apply(&[("max_resolution", "720p")]);
+        assert_eq!(uris(&filtered), ["720p60", "480p30", "audio_only"]);
+        assert!(!filtered.contains("GROUP-ID=\"chunked\""));
+        assert!(filtered.starts_with("#EXTM3U\n#EXT-X-TWITCH-INFO"));
+        assert_eq!(uris(&apply(&[("max_bitrate", "2000")])), ["480p30", "audio_only"]);
+        assert_eq!(uris(&apply(&[("codecs", "av1")])), ["chunked", "audio_only"]);
+        assert_eq!(uris(&apply(&[("audio_only", "true")])), ["audio_only"]);
+        assert_eq!(uris(&apply(&[("max_bitrate", "1")])), ["audio_only"]); // smallest
+        assert!(base.with_query(&query(&[("codecs", "vp9")])).is_err());
+        assert!(base.with_query(&query(&[("max_bitrate", &u64::MAX.to_string())])).is_err());
+    }
+
+    #[test]
+    fn bitrate_option_fits_bandwidth() {
+        use clap::Parser;
+        let parse = |kbps: u64| {
+            let kbps = kbps.to_string();
+            crate::Opts::try_parse_from(["x", "--proxy", "http://[::1]:1", "--max-bitrate", &kbps])
+        };
+        assert_eq!(VariantFilter::new(&parse(3000).unwrap()).max_bandwidth, Some(3_000_000));
+        assert!(parse(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn server_limits_only_tighten() {
+        let server = VariantFilter {
+            max_height: Some(720),
+            codecs: vec![Codec::H264],
+            ..Default::default()
+        };
+        let apply = |pairs| run(&server.with_query(&query(pairs)).unwrap(), MASTER);
+        assert_eq!(uris(&apply(&[])), ["720p60", "480p30", "audio_only"]);
+        assert_eq!(uris(&apply(&[("max_resolution", "1080")])), ["720p60", "480p30", "audio_only"]);
+        assert_eq!(uris(&apply(&[("codecs", "av1")])), ["720p60", "480p30", "audio_only"]);
+    }
+
+    #[test]
+    fn preferred_first() {
+        let filter = VariantFilter::default().with_query(&query(&[("prefer", "480,720")])).unwrap();
+        let reordered = run(&filter, MASTER);
+        assert_eq!(uris(&reordered), ["480p30", "720p60", "chunked", "audio_only"]);
+        // each variant's media tag moves with it
+        let lines: Vec<_> = reordered.lines().collect();
+        assert!(lines[2].contains("GROUP-ID=\"480p30\""));
+        assert!(lines[5].contains("GROUP-ID=\"720p60\""));
+    }
+
+    #[test]
+    fn best_after_filtering() {
+        let mut playlist = Playlist::parse(MASTER);
+        assert_eq!(best(&playlist), Some("https://cdn.example/chunked.m3u8"));
+        let filter =
+            VariantFilter::default().with_query(&query(&[("max_resolution", "720")])).unwrap();
+        filter.apply(&mut playlist);
+        assert_eq!(best(&playlist), Some("https://cdn.example/720p60.m3u8"));
+        assert_eq!(best(&Playlist::parse("#EXTM3U\n")), None);
+    }
+}